@@ -7,6 +7,7 @@ func _enter_tree():
 	add_custom_type("FlowFieldTileCost", "Node", preload("res://addons/tilemap_flowfields/classes/flow_field_tile_cost.gdns"), preload("res://addons/tilemap_flowfields/icons/cost.svg"))
 	add_custom_type("FlowField", "Resource", preload("res://addons/tilemap_flowfields/classes/flow_field.gdns"), null)
 	add_custom_type("BakedFlowFields", "Resource", preload("res://addons/tilemap_flowfields/classes/baked_flow_fields.gdns"), null)
+	add_custom_type("SectorFlowField", "Resource", preload("res://addons/tilemap_flowfields/classes/sector_flow_field.gdns"), null)
 
 
 func _exit_tree():
@@ -14,3 +15,4 @@ func _exit_tree():
 	remove_custom_type("FlowFieldTileCost")
 	remove_custom_type("FlowField")
 	remove_custom_type("BakedFlowFields")
+	remove_custom_type("SectorFlowField")
@@ -1,7 +1,12 @@
 use rayon::prelude::*;
-use std::collections::VecDeque;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
 use std::f32::consts::{FRAC_1_SQRT_2, SQRT_2};
 
+/// Height of the equilateral triangle between three hexagonal tile centers.
+const HEX_HEIGHT: f32 = 0.866_025_4;
+
+#[derive(Clone, Copy)]
 enum Dir {
     North,
     NorthEast,
@@ -14,6 +19,17 @@ enum Dir {
 }
 
 impl Dir {
+    const ALL: [Dir; 8] = [
+        Dir::North,
+        Dir::NorthEast,
+        Dir::East,
+        Dir::SouthEast,
+        Dir::South,
+        Dir::SouthWest,
+        Dir::West,
+        Dir::NorthWest,
+    ];
+
     fn offset(&self) -> (isize, isize) {
         use crate::algo::Dir::*;
         match self {
@@ -28,6 +44,26 @@ impl Dir {
         }
     }
 
+    /// The two orthogonal directions a diagonal passes between, `None` for orthogonal directions.
+    fn orthogonals(&self) -> Option<(Dir, Dir)> {
+        use crate::algo::Dir::*;
+        match self {
+            North | East | South | West => None,
+            NorthEast => Some((North, East)),
+            SouthEast => Some((South, East)),
+            SouthWest => Some((South, West)),
+            NorthWest => Some((North, West)),
+        }
+    }
+
+    fn step(&self) -> Step {
+        Step {
+            offset: self.offset(),
+            distance: self.distance(),
+            flow_vec: self.flow_vec(),
+        }
+    }
+
     fn distance(&self) -> f32 {
         use crate::algo::Dir::*;
         match self {
@@ -60,9 +96,189 @@ impl Dir {
     }
 }
 
+/// A single step from a tile to one of its neighbors.
+#[derive(Clone, Copy)]
+struct Step {
+    offset: Coord,
+    distance: f32,
+    /// Normalized direction of the step, in the map's geometry.
+    flow_vec: Vector2D,
+}
+
+/// Which neighbors of a tile can be walked to directly.
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    gdnative::prelude::FromVariant,
+    gdnative::prelude::ToVariant,
+)]
+pub enum Neighborhood {
+    /// The four orthogonal neighbors only.
+    Four,
+    /// All eight neighbors, diagonals only if both orthogonal tiles beside them are passable.
+    #[default]
+    Eight,
+    /// All eight neighbors, diagonals may cut past an impassable corner,
+    /// but not squeeze between two impassable tiles.
+    EightCornerCutting,
+    /// Six neighbors of hexagonal tiles in rows, every odd row shifted right by half a tile.
+    HexOddRows,
+    /// Six neighbors of hexagonal tiles in rows, every even row shifted right by half a tile.
+    HexEvenRows,
+    /// Six neighbors of hexagonal tiles in columns, every odd column shifted down by half a tile.
+    HexOddColumns,
+    /// Six neighbors of hexagonal tiles in columns, every even column shifted down by half a tile.
+    HexEvenColumns,
+}
+
+impl Neighborhood {
+    /// Names of the neighborhoods of square tiles, which can be chosen freely.
+    pub const SQUARE_NAMES: [&'static str; 3] = ["Four", "Eight", "EightCornerCutting"];
+
+    pub fn from_i64(v: i64) -> Self {
+        match v {
+            0 => Neighborhood::Four,
+            2 => Neighborhood::EightCornerCutting,
+            3 => Neighborhood::HexOddRows,
+            4 => Neighborhood::HexEvenRows,
+            5 => Neighborhood::HexOddColumns,
+            6 => Neighborhood::HexEvenColumns,
+            _ => Neighborhood::Eight,
+        }
+    }
+
+    pub fn is_hex(&self) -> bool {
+        !matches!(
+            self,
+            Neighborhood::Four | Neighborhood::Eight | Neighborhood::EightCornerCutting
+        )
+    }
+
+    /// The neighborhood of the same tiles, within a field whose origin lies at the given tile.
+    /// Hex layouts swap their shifted rows or columns if the origin lies on an odd one.
+    pub fn translated(self, (x, y): Coord) -> Self {
+        use crate::algo::Neighborhood::*;
+        match self {
+            HexOddRows if y.rem_euclid(2) == 1 => HexEvenRows,
+            HexEvenRows if y.rem_euclid(2) == 1 => HexOddRows,
+            HexOddColumns if x.rem_euclid(2) == 1 => HexEvenColumns,
+            HexEvenColumns if x.rem_euclid(2) == 1 => HexOddColumns,
+            _ => self,
+        }
+    }
+
+    /// Converts a flow vector into cell units, e.g. to apply a TileMap's cell transform.
+    /// Hex rows or columns lie closer together than the tiles in them, which cells do not account for.
+    pub fn cell_vec(&self, (vx, vy): Vector2D) -> Vector2D {
+        use crate::algo::Neighborhood::*;
+        match self {
+            HexOddRows | HexEvenRows => (vx, vy / HEX_HEIGHT),
+            HexOddColumns | HexEvenColumns => (vx / HEX_HEIGHT, vy),
+            Four | Eight | EightCornerCutting => (vx, vy),
+        }
+    }
+
+    /// Center of the tile in the map's geometry, where neighboring hexagons lie one apart.
+    pub fn center(&self, (x, y): Coord) -> Vector2D {
+        use crate::algo::Neighborhood::*;
+        let shift = |shifted: bool| if shifted { 0.5 } else { 0. };
+        match self {
+            HexOddRows | HexEvenRows => (
+                x as f32 + shift((y.rem_euclid(2) == 1) == (*self == HexOddRows)),
+                y as f32 * HEX_HEIGHT,
+            ),
+            HexOddColumns | HexEvenColumns => (
+                x as f32 * HEX_HEIGHT,
+                y as f32 + shift((x.rem_euclid(2) == 1) == (*self == HexOddColumns)),
+            ),
+            Four | Eight | EightCornerCutting => (x as f32, y as f32),
+        }
+    }
+
+    /// Steps to the neighbors the tile can walk to, given the passability of the tiles around it.
+    fn steps(
+        &self,
+        (x, y): Coord,
+        passable_at: impl Fn(Coord) -> bool,
+    ) -> impl Iterator<Item = Step> {
+        use crate::algo::Neighborhood::*;
+        let passable_towards = |dir: Dir| {
+            let (off_x, off_y) = dir.offset();
+            passable_at((x + off_x, y + off_y))
+        };
+        let mut steps = [None; 8];
+        match self {
+            Four | Eight | EightCornerCutting => {
+                for (step, dir) in steps.iter_mut().zip(Dir::ALL) {
+                    let allowed = match (self, dir.orthogonals()) {
+                        (_, None) => true,
+                        (Four, Some(_)) => false,
+                        (EightCornerCutting, Some((a, b))) => {
+                            passable_towards(a) || passable_towards(b)
+                        }
+                        (_, Some((a, b))) => passable_towards(a) && passable_towards(b),
+                    };
+                    *step = allowed.then(|| dir.step());
+                }
+            }
+            HexOddRows | HexEvenRows | HexOddColumns | HexEvenColumns => {
+                for (step, hex_step) in steps.iter_mut().zip(self.hex_steps((x, y))) {
+                    *step = Some(hex_step);
+                }
+            }
+        }
+        steps.into_iter().flatten()
+    }
+
+    /// Steps to the six neighbors of a hexagonal tile, clockwise.
+    /// Directions assume regular hexagons, every step has a distance of one.
+    fn hex_steps(&self, (x, y): Coord) -> [Step; 6] {
+        use crate::algo::Neighborhood::*;
+        const H: f32 = HEX_HEIGHT;
+        let hex = |offset, flow_vec| Step {
+            offset,
+            distance: 1.,
+            flow_vec,
+        };
+        match self {
+            HexOddRows | HexEvenRows => {
+                let shifted = (y.rem_euclid(2) == 1) == (*self == HexOddRows);
+                let (left, right) = if shifted { (0, 1) } else { (-1, 0) };
+                [
+                    hex((right, -1), (0.5, -H)),
+                    hex((1, 0), (1., 0.)),
+                    hex((right, 1), (0.5, H)),
+                    hex((left, 1), (-0.5, H)),
+                    hex((-1, 0), (-1., 0.)),
+                    hex((left, -1), (-0.5, -H)),
+                ]
+            }
+            _ => {
+                let shifted = (x.rem_euclid(2) == 1) == (*self == HexOddColumns);
+                let (up, down) = if shifted { (0, 1) } else { (-1, 0) };
+                [
+                    hex((0, -1), (0., -1.)),
+                    hex((1, up), (H, -0.5)),
+                    hex((1, down), (H, 0.5)),
+                    hex((0, 1), (0., 1.)),
+                    hex((-1, down), (-H, 0.5)),
+                    hex((-1, up), (-H, -0.5)),
+                ]
+            }
+        }
+    }
+}
+
 #[derive(
     Clone,
     Copy,
+    PartialEq,
+    Eq,
     serde::Serialize,
     serde::Deserialize,
     gdnative::prelude::FromVariant,
@@ -72,6 +288,8 @@ pub struct Dimensions {
     width: usize,
     height: usize,
     max_idx: usize,
+    /// TileMap cell of the field's first tile, all algorithms work relative to it.
+    origin: Coord,
 }
 impl Dimensions {
     pub fn new(width: usize, height: usize) -> Self {
@@ -79,8 +297,35 @@ impl Dimensions {
             width,
             height,
             max_idx: (width * height),
+            origin: (0, 0),
         }
     }
+    pub fn with_origin(mut self, origin: Coord) -> Self {
+        self.origin = origin;
+        self
+    }
+    pub fn origin(&self) -> Coord {
+        self.origin
+    }
+    /// Converts a TileMap cell into coordinates within the field.
+    /// Cells too far off to convert end up at the edge of the coordinates, still out of bounds.
+    pub fn cell_to_local(&self, (x, y): Coord) -> Coord {
+        (
+            x.saturating_sub(self.origin.0),
+            y.saturating_sub(self.origin.1),
+        )
+    }
+    /// Converts coordinates within the field into a TileMap cell, saturating like `cell_to_local`.
+    pub fn local_to_cell(&self, (x, y): Coord) -> Coord {
+        (
+            x.saturating_add(self.origin.0),
+            y.saturating_add(self.origin.1),
+        )
+    }
+    /// Converts a continuous position in map space into the field, like `cell_to_local`.
+    pub fn pos_to_local(&self, (px, py): Vector2D) -> Vector2D {
+        (px - self.origin.0 as f32, py - self.origin.1 as f32)
+    }
     pub fn project_to_field_idx(&self, x: isize, y: isize) -> usize {
         (x + y * self.width as isize) as usize
     }
@@ -108,182 +353,1054 @@ pub type Cost = Option<f32>;
 pub type CostField = Vec<Cost>;
 pub type IntegrationField = Vec<Cost>;
 pub type FlowField = Vec<Option<Vector2D>>;
+/// Region id of each passable tile, tiles of the same region are connected.
+pub type RegionField = Vec<Option<usize>>;
+/// Whether each tile lies beyond the maximum cost an integration stopped at.
+pub type RangeField = Vec<bool>;
+/// A target tile together with the integration cost it starts out with.
+pub type Target = (Coord, f32);
+/// Extra cost of entering a tile while moving north, east, south or west.
+/// `None` forbids entering the tile in that direction.
+pub type EntryCost = [Cost; 4];
+/// Entry costs of every tile, `None` for tiles that can be entered freely from every direction.
+pub type EntryCostField = Vec<Option<EntryCost>>;
+
+/// Entry of the integration frontier. Ordered so that `BinaryHeap` pops the cheapest tile first.
+#[derive(PartialEq)]
+pub(crate) struct Frontier {
+    pub(crate) cost: f32,
+    pub(crate) idx: usize,
+}
+
+impl Eq for Frontier {}
+
+impl Ord for Frontier {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .cost
+            .total_cmp(&self.cost)
+            .then_with(|| other.idx.cmp(&self.idx))
+    }
+}
+
+impl PartialOrd for Frontier {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Initializes the integration field and frontier with the passable, in-bounds targets.
+/// Returns `None` if there are none.
+fn seed_targets(
+    dim: &Dimensions,
+    targets: &[Target],
+    cost_field: &CostField,
+) -> Option<(IntegrationField, BinaryHeap<Frontier>)> {
+    let mut integration_field: IntegrationField = vec![None; dim.max_idx];
+    let mut queue: BinaryHeap<Frontier> = BinaryHeap::new();
+    for &((to_x, to_y), initial_cost) in targets {
+        if !dim.in_bounds(to_x, to_y) {
+            continue;
+        }
+        let idx = dim.project_to_field_idx(to_x, to_y);
+        if cost_field[idx].is_none() {
+            continue;
+        }
+        match integration_field[idx] {
+            Some(c) if c <= initial_cost => (),
+            _ => {
+                integration_field[idx] = Some(initial_cost);
+                queue.push(Frontier {
+                    cost: initial_cost,
+                    idx,
+                });
+            }
+        }
+    }
+    if queue.is_empty() {
+        None
+    } else {
+        Some((integration_field, queue))
+    }
+}
+
+/// Extra cost of entering the tile while moving along the vector, `None` if that is forbidden.
+/// Diagonal moves must be allowed in both of their directions, and pay the higher of both costs.
+fn entry_cost(entry_costs: Option<&EntryCostField>, idx: usize, (vx, vy): Vector2D) -> Cost {
+    match entry_costs.and_then(|entry_costs| entry_costs[idx]) {
+        None => Some(0.),
+        Some(costs) => costs
+            .iter()
+            .zip([vy < 0., vx > 0., vy > 0., vx < 0.])
+            .filter(|(_, moving)| *moving)
+            .try_fold(0f32, |highest, (cost, _)| cost.map(|c| highest.max(c))),
+    }
+}
+
+/// Relaxes the neighbors of a tile with its current integration,
+/// pushing every neighbor whose integration improves onto the frontier.
+/// Agents walk from the neighbors onto the tile, so they pay the tile's entry cost.
+fn expand(
+    dim: &Dimensions,
+    neighborhood: Neighborhood,
+    cost_field: &CostField,
+    entry_costs: Option<&EntryCostField>,
+    idx: usize,
+    integration_field: &mut IntegrationField,
+    queue: &mut BinaryHeap<Frontier>,
+) {
+    let current_cost = match integration_field[idx] {
+        Some(c) => c,
+        None => return,
+    };
+    let (x, y) = dim.unproject_to_field_coords(idx);
+
+    let passable_at =
+        |(x, y): Coord| dim.in_bounds(x, y) && cost_field[dim.project_to_field_idx(x, y)].is_some();
+
+    for step in neighborhood.steps((x, y), passable_at) {
+        let (off_x, off_y) = step.offset;
+        let (x_next, y_next) = (x + off_x, y + off_y);
+        let (vx, vy) = step.flow_vec;
+        if dim.in_bounds(x_next, y_next) {
+            let idx_next = dim.project_to_field_idx(x_next, y_next);
+            if let (Some(c_static), Some(c_entry)) = (
+                cost_field[idx_next],
+                entry_cost(entry_costs, idx, (-vx, -vy)),
+            ) {
+                let new_cost = step.distance + c_static + c_entry + current_cost;
+                match integration_field[idx_next] {
+                    Some(old_cost) if old_cost <= new_cost => (),
+                    _ => {
+                        integration_field[idx_next] = Some(new_cost);
+                        queue.push(Frontier {
+                            cost: new_cost,
+                            idx: idx_next,
+                        })
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Integration the neighbors of a tile would get by expanding it with the given cost,
+/// without the tile itself being part of the field, e.g. because it lies outside of a sub-field.
+pub(crate) fn neighbor_seeds(
+    dim: &Dimensions,
+    neighborhood: Neighborhood,
+    cost_field: &CostField,
+    (x, y): Coord,
+    current_cost: f32,
+) -> Vec<Target> {
+    let passable_at =
+        |(x, y): Coord| dim.in_bounds(x, y) && cost_field[dim.project_to_field_idx(x, y)].is_some();
+    neighborhood
+        .steps((x, y), passable_at)
+        .filter_map(|step| {
+            let (off_x, off_y) = step.offset;
+            let (x_next, y_next) = (x + off_x, y + off_y);
+            if !dim.in_bounds(x_next, y_next) {
+                return None;
+            }
+            cost_field[dim.project_to_field_idx(x_next, y_next)]
+                .map(|c_static| ((x_next, y_next), step.distance + c_static + current_cost))
+        })
+        .collect()
+}
+
+/// Passable tiles a tile can walk to directly under the neighborhood.
+pub(crate) fn passable_neighbors(
+    dim: &Dimensions,
+    neighborhood: Neighborhood,
+    cost_field: &CostField,
+    (x, y): Coord,
+) -> Vec<Coord> {
+    let passable_at =
+        |(x, y): Coord| dim.in_bounds(x, y) && cost_field[dim.project_to_field_idx(x, y)].is_some();
+    neighborhood
+        .steps((x, y), passable_at)
+        .map(|step| (x + step.offset.0, y + step.offset.1))
+        .filter(|&next| passable_at(next))
+        .collect()
+}
 
+/// Clearance of every tile: the size of the largest square of passable tiles
+/// with the tile as its top-left corner. Impassable tiles have a clearance of zero.
+pub fn calculate_clearance(dim: &Dimensions, cost_field: &CostField) -> Vec<usize> {
+    let mut clearance = vec![0; dim.max_idx()];
+    for y in (0..dim.height() as isize).rev() {
+        for x in (0..dim.width() as isize).rev() {
+            let idx = dim.project_to_field_idx(x, y);
+            if cost_field[idx].is_some() {
+                let at = |(x, y)| {
+                    if dim.in_bounds(x, y) {
+                        clearance[dim.project_to_field_idx(x, y)]
+                    } else {
+                        0
+                    }
+                };
+                clearance[idx] = 1 + at((x + 1, y)).min(at((x, y + 1))).min(at((x + 1, y + 1)));
+            }
+        }
+    }
+    clearance
+}
+
+/// Cost field for units covering `unit_size` by `unit_size` tiles, positioned by their top-left tile.
+/// Tiles without enough clearance for the unit become impassable.
+pub fn restrict_to_clearance(
+    dim: &Dimensions,
+    cost_field: CostField,
+    unit_size: usize,
+) -> CostField {
+    if unit_size <= 1 {
+        return cost_field;
+    }
+    let clearance = calculate_clearance(dim, &cost_field);
+    cost_field
+        .into_iter()
+        .zip(clearance)
+        .map(|(cost, clearance)| cost.filter(|_| clearance >= unit_size))
+        .collect()
+}
+
+/// Tiles whose clearance for units of `unit_size` may depend on the given tiles:
+/// the tiles themselves, and those less than `unit_size` tiles above and left of them.
+pub fn clearance_dependents(tiles: &[Coord], unit_size: usize) -> Vec<Coord> {
+    let reach = unit_size.max(1) as isize;
+    tiles
+        .iter()
+        .flat_map(|&(x, y)| {
+            (0..reach).flat_map(move |dy| (0..reach).map(move |dx| (x - dx, y - dy)))
+        })
+        .collect()
+}
+
+/// Dijkstra over the cost field, starting at all of the given targets with their initial cost.
+/// Every tile is expanded exactly once, when it is popped with its final cost,
+/// so each tile ends up with the cost towards its cheapest target.
+/// Targets that are out of bounds or impassable are ignored, if none remain `None` is returned.
+/// Entry costs, if given, make the edges between tiles asymmetric.
 pub fn calculate_integration_field(
     dim: &Dimensions,
-    (to_x, to_y): Coord,
+    neighborhood: Neighborhood,
+    targets: &[Target],
     cost_field: &CostField,
+    entry_costs: Option<&EntryCostField>,
 ) -> Option<IntegrationField> {
-    use crate::algo::Dir::*;
+    calculate_integration_field_within(
+        dim,
+        neighborhood,
+        targets,
+        cost_field,
+        entry_costs,
+        f32::INFINITY,
+    )
+    .map(|(integration_field, _)| integration_field)
+}
+
+/// Like `calculate_integration_field`, but stops once the cheapest tile left would cost more than `max_cost`.
+/// Tiles beyond it are left without integration, like unreachable ones.
+/// Also marks the tiles beyond `max_cost`, which could reach a target if it weren't for the cut.
+pub fn calculate_integration_field_within(
+    dim: &Dimensions,
+    neighborhood: Neighborhood,
+    targets: &[Target],
+    cost_field: &CostField,
+    entry_costs: Option<&EntryCostField>,
+    max_cost: f32,
+) -> Option<(IntegrationField, RangeField)> {
     assert_eq!(
         dim.max_idx(),
         cost_field.len(),
         "Cost field size does not match dimensions!"
     );
-    let mut integration_field: IntegrationField = vec![None; dim.max_idx];
-    let initial_cost = cost_field[dim.project_to_field_idx(to_x, to_y)];
-    initial_cost?;
-    integration_field[dim.project_to_field_idx(to_x, to_y)] = Some(0.);
-
-    let mut queue: VecDeque<Coord> = vec![(to_x, to_y)].into();
-    while !queue.is_empty() {
-        match queue.pop_front() {
-            None => (), // queue empty.
-            Some((x, y)) => {
-                let current_cost = integration_field[dim.project_to_field_idx(x, y)];
-                if current_cost.is_none() {
+    let (mut integration_field, mut queue) = seed_targets(dim, targets, cost_field)?;
+    let mut settled = vec![false; dim.max_idx];
+
+    while let Some(Frontier { cost, idx }) = queue.pop() {
+        if settled[idx] {
+            continue; // stale entry, tile was already expanded with a lower cost.
+        }
+        if cost > max_cost {
+            /* every tile with a tentative cost is still on the frontier, all of them lie beyond. */
+            queue.push(Frontier { cost, idx });
+            let frontier = cut_at_frontier(&mut integration_field, &settled, queue);
+            let beyond = mark_beyond(
+                dim,
+                neighborhood,
+                cost_field,
+                entry_costs,
+                &integration_field,
+                frontier,
+            );
+            return Some((integration_field, beyond));
+        }
+        settled[idx] = true;
+        expand(
+            dim,
+            neighborhood,
+            cost_field,
+            entry_costs,
+            idx,
+            &mut integration_field,
+            &mut queue,
+        );
+    }
+    Some((integration_field, vec![false; dim.max_idx()]))
+}
+
+/// Clears the integration of the tiles left on the frontier when a search is cut short,
+/// and returns them.
+fn cut_at_frontier(
+    integration_field: &mut IntegrationField,
+    settled: &[bool],
+    queue: BinaryHeap<Frontier>,
+) -> Vec<usize> {
+    let mut frontier = vec![];
+    for Frontier { idx, .. } in queue {
+        /* a tile may be queued several times, but its integration is only taken once. */
+        if !settled[idx] && integration_field[idx].take().is_some() {
+            frontier.push(idx);
+        }
+    }
+    frontier
+}
+
+/// Marks the tiles beyond the frontier a search was cut short at: the frontier itself,
+/// and every tile without integration that could walk onto it, given the neighborhood and entry costs.
+/// Impassable tiles are never marked.
+fn mark_beyond(
+    dim: &Dimensions,
+    neighborhood: Neighborhood,
+    cost_field: &CostField,
+    entry_costs: Option<&EntryCostField>,
+    integration_field: &IntegrationField,
+    frontier: Vec<usize>,
+) -> RangeField {
+    let mut beyond = vec![false; dim.max_idx()];
+    for &idx in &frontier {
+        beyond[idx] = true;
+    }
+    let passable_at =
+        |(x, y): Coord| dim.in_bounds(x, y) && cost_field[dim.project_to_field_idx(x, y)].is_some();
+    let mut stack = frontier;
+    while let Some(idx) = stack.pop() {
+        let (x, y) = dim.unproject_to_field_coords(idx);
+        for step in neighborhood.steps((x, y), passable_at) {
+            let (off_x, off_y) = step.offset;
+            let (x_next, y_next) = (x + off_x, y + off_y);
+            let (vx, vy) = step.flow_vec;
+            if !dim.in_bounds(x_next, y_next) {
+                continue;
+            }
+            let idx_next = dim.project_to_field_idx(x_next, y_next);
+            if !beyond[idx_next]
+                && integration_field[idx_next].is_none()
+                && cost_field[idx_next].is_some()
+                && entry_cost(entry_costs, idx, (-vx, -vy)).is_some()
+            {
+                beyond[idx_next] = true;
+                stack.push(idx_next);
+            }
+        }
+    }
+    beyond
+}
+
+/// Factor by which the distance to threats is inverted for flee fields.
+/// Above one, so that agents rather run past a threat towards safety than corner themselves.
+const FLEE_FACTOR: f32 = 1.2;
+
+/// Targets of a flee field, roguelike "safety map" style: every passable tile starts out
+/// with its distance to the nearest threat, capped at `safety_distance`, inverted and rescaled.
+/// Integrating towards them rewards distance from the threats, while respecting the map's walls.
+/// Tiles that can't reach any threat count as being as far away as the farthest tile that can.
+pub fn flee_targets(
+    dim: &Dimensions,
+    cost_field: &CostField,
+    towards_threats: &IntegrationField,
+    safety_distance: f32,
+) -> Vec<Target> {
+    let farthest = towards_threats
+        .iter()
+        .flatten()
+        .fold(0f32, |farthest, &c| farthest.max(c))
+        .min(safety_distance);
+    (0..dim.max_idx())
+        .filter(|&idx| cost_field[idx].is_some())
+        .map(|idx| {
+            let distance = towards_threats[idx].map_or(farthest, |c| c.min(safety_distance));
+            (dim.unproject_to_field_coords(idx), -FLEE_FACTOR * distance)
+        })
+        .collect()
+}
+
+/// Repairs a Dijkstra integration field after the costs of some tiles changed.
+/// Invalidates every tile whose cost was derived via a changed tile, or via a diagonal that
+/// a changed tile may have opened or closed, and propagates again from the region's border.
+/// The result is identical to `calculate_integration_field` on the changed cost field.
+pub fn repair_integration_field(
+    dim: &Dimensions,
+    neighborhood: Neighborhood,
+    targets: &[Target],
+    cost_field: &CostField,
+    entry_costs: Option<&EntryCostField>,
+    integration_field: &mut IntegrationField,
+    changed: &[Coord],
+) {
+    assert_eq!(
+        dim.max_idx(),
+        cost_field.len(),
+        "Cost field size does not match dimensions!"
+    );
+    assert_eq!(
+        dim.max_idx(),
+        integration_field.len(),
+        "Integration field size does not match dimensions!"
+    );
+    /* every tile that may be a neighbor, regardless of passability. */
+    let neighbors = |idx: usize| {
+        let (x, y) = dim.unproject_to_field_coords(idx);
+        neighborhood
+            .steps((x, y), |_| true)
+            .filter_map(move |step| {
+                let (off_x, off_y) = step.offset;
+                let (x_next, y_next) = (x + off_x, y + off_y);
+                dim.in_bounds(x_next, y_next)
+                    .then(|| (step, dim.project_to_field_idx(x_next, y_next)))
+            })
+    };
+
+    let mut invalid = vec![false; dim.max_idx()];
+    let mut stack: Vec<usize> = vec![];
+    for &(x, y) in changed {
+        if !dim.in_bounds(x, y) {
+            continue;
+        }
+        let idx = dim.project_to_field_idx(x, y);
+        for idx in std::iter::once(idx).chain(neighbors(idx).map(|(_, n)| n)) {
+            if !invalid[idx] {
+                invalid[idx] = true;
+                stack.push(idx);
+            }
+        }
+    }
+    /* a tile's cost was derived from a neighbor iff it is exactly that neighbor's cost plus the step. */
+    while let Some(idx) = stack.pop() {
+        if let Some(current_cost) = integration_field[idx] {
+            for (step, idx_next) in neighbors(idx) {
+                if invalid[idx_next] {
                     continue;
                 }
+                let (vx, vy) = step.flow_vec;
+                if let (Some(c_static), Some(c_entry), Some(old_cost)) = (
+                    cost_field[idx_next],
+                    entry_cost(entry_costs, idx, (-vx, -vy)),
+                    integration_field[idx_next],
+                ) {
+                    if old_cost == step.distance + c_static + c_entry + current_cost {
+                        invalid[idx_next] = true;
+                        stack.push(idx_next);
+                    }
+                }
+            }
+        }
+    }
 
-                let passable_at = |dir: Dir| {
-                    let (off_x, off_y) = dir.offset();
-                    let (x_next, y_next) = (x + off_x, y + off_y);
-                    dim.in_bounds(x_next, y_next)
-                        && cost_field[dim.project_to_field_idx(x_next, y_next)].is_some()
-                };
+    let mut queue: BinaryHeap<Frontier> = BinaryHeap::new();
+    for (idx, is_invalid) in invalid.iter().enumerate() {
+        if *is_invalid {
+            integration_field[idx] = None;
+        }
+    }
+    for &((to_x, to_y), initial_cost) in targets {
+        if !dim.in_bounds(to_x, to_y) {
+            continue;
+        }
+        let idx = dim.project_to_field_idx(to_x, to_y);
+        if !invalid[idx] || cost_field[idx].is_none() {
+            continue;
+        }
+        match integration_field[idx] {
+            Some(c) if c <= initial_cost => (),
+            _ => {
+                integration_field[idx] = Some(initial_cost);
+                queue.push(Frontier {
+                    cost: initial_cost,
+                    idx,
+                });
+            }
+        }
+    }
+    /* valid tiles bordering the invalidated region propagate into it. */
+    for idx in 0..dim.max_idx() {
+        if invalid[idx] || !neighbors(idx).any(|(_, n)| invalid[n]) {
+            continue;
+        }
+        expand(
+            dim,
+            neighborhood,
+            cost_field,
+            entry_costs,
+            idx,
+            integration_field,
+            &mut queue,
+        );
+    }
+    while let Some(Frontier {
+        cost: current_cost,
+        idx,
+    }) = queue.pop()
+    {
+        if integration_field[idx] != Some(current_cost) {
+            continue; // stale entry, tile was improved since.
+        }
+        expand(
+            dim,
+            neighborhood,
+            cost_field,
+            entry_costs,
+            idx,
+            integration_field,
+            &mut queue,
+        );
+    }
+}
 
-                let mut visit = |dir: Dir| {
-                    let (off_x, off_y) = dir.offset();
-                    let (x_next, y_next) = (x + off_x, y + off_y);
-                    if dim.in_bounds(x_next, y_next) {
-                        let opt_new_cost = cost_field[dim.project_to_field_idx(x_next, y_next)]
-                            .and_then(|c_static| {
-                                current_cost.map(|c_acc| dir.distance() + c_static + c_acc)
-                            });
-                        let old_cost = integration_field[dim.project_to_field_idx(x_next, y_next)];
-                        match (opt_new_cost, old_cost) {
-                            (Some(n), Some(o)) if n < o => {
-                                integration_field[dim.project_to_field_idx(x_next, y_next)] =
-                                    opt_new_cost;
-                                queue.push_back((x_next, y_next))
-                            }
-                            (Some(_), None) => {
-                                integration_field[dim.project_to_field_idx(x_next, y_next)] =
-                                    opt_new_cost;
-                                queue.push_back((x_next, y_next))
-                            }
-                            _ => (),
-                        }
-                    }
-                };
+/// Fast Marching solver of the Eikonal equation, as an alternative to the graph-based integration.
+/// Arrival times are near-Euclidean without the octile artifacts of `calculate_integration_field`.
+/// The slowness of a tile is `1 + cost`, matching the graph-based costs along straight lines.
+/// Targets that are out of bounds or impassable are ignored, if none remain `None` is returned.
+pub fn calculate_eikonal_integration_field(
+    dim: &Dimensions,
+    targets: &[Target],
+    cost_field: &CostField,
+) -> Option<IntegrationField> {
+    calculate_eikonal_integration_field_within(dim, targets, cost_field, f32::INFINITY)
+        .map(|(integration_field, _)| integration_field)
+}
 
-                if passable_at(North) && passable_at(East) {
-                    visit(NorthEast);
-                }
-                if passable_at(South) && passable_at(East) {
-                    visit(SouthEast);
+/// Like `calculate_eikonal_integration_field`, but stops at the first tile arriving later than `max_cost`.
+/// Tiles beyond it are left without integration, like unreachable ones.
+/// Also marks the tiles beyond `max_cost`, which could reach a target if it weren't for the cut.
+pub fn calculate_eikonal_integration_field_within(
+    dim: &Dimensions,
+    targets: &[Target],
+    cost_field: &CostField,
+    max_cost: f32,
+) -> Option<(IntegrationField, RangeField)> {
+    assert_eq!(
+        dim.max_idx(),
+        cost_field.len(),
+        "Cost field size does not match dimensions!"
+    );
+    let (mut integration_field, mut queue) = seed_targets(dim, targets, cost_field)?;
+    let mut settled = vec![false; dim.max_idx];
+
+    while let Some(Frontier { cost, idx }) = queue.pop() {
+        if settled[idx] {
+            continue; // stale entry, tile was already accepted with a lower arrival time.
+        }
+        if cost > max_cost {
+            /* arrival times only grow from here on, every tile left on the frontier lies beyond. */
+            queue.push(Frontier { cost, idx });
+            let frontier = cut_at_frontier(&mut integration_field, &settled, queue);
+            let beyond = mark_beyond(
+                dim,
+                Neighborhood::Four,
+                cost_field,
+                None,
+                &integration_field,
+                frontier,
+            );
+            return Some((integration_field, beyond));
+        }
+        settled[idx] = true;
+        let (x, y) = dim.unproject_to_field_coords(idx);
+
+        for (x_next, y_next) in [(x, y - 1), (x + 1, y), (x, y + 1), (x - 1, y)] {
+            if !dim.in_bounds(x_next, y_next) {
+                continue;
+            }
+            let idx_next = dim.project_to_field_idx(x_next, y_next);
+            if settled[idx_next] {
+                continue;
+            }
+            let slowness = match cost_field[idx_next] {
+                Some(c_static) => 1. + c_static,
+                None => continue,
+            };
+            /* upwind arrival times of the already accepted neighbors along either axis. */
+            let accepted_at = |x, y| {
+                if dim.in_bounds(x, y) && settled[dim.project_to_field_idx(x, y)] {
+                    integration_field[dim.project_to_field_idx(x, y)]
+                } else {
+                    None
                 }
-                if passable_at(South) && passable_at(West) {
-                    visit(SouthWest);
+            };
+            let min_of = |a: Option<f32>, b: Option<f32>| match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, None) => a,
+                (None, b) => b,
+            };
+            let horizontal = min_of(
+                accepted_at(x_next - 1, y_next),
+                accepted_at(x_next + 1, y_next),
+            );
+            let vertical = min_of(
+                accepted_at(x_next, y_next - 1),
+                accepted_at(x_next, y_next + 1),
+            );
+            let new_cost = match (horizontal, vertical) {
+                (Some(a), Some(b)) if (a - b).abs() < slowness => {
+                    (a + b + (2. * slowness * slowness - (a - b) * (a - b)).sqrt()) / 2.
                 }
-                if passable_at(North) && passable_at(West) {
-                    visit(NorthWest);
+                (Some(a), Some(b)) => a.min(b) + slowness,
+                (Some(a), None) | (None, Some(a)) => a + slowness,
+                (None, None) => continue,
+            };
+            match integration_field[idx_next] {
+                Some(old_cost) if old_cost <= new_cost => (),
+                _ => {
+                    integration_field[idx_next] = Some(new_cost);
+                    queue.push(Frontier {
+                        cost: new_cost,
+                        idx: idx_next,
+                    })
                 }
-                visit(North);
-                visit(East);
-                visit(South);
-                visit(West);
             }
         }
     }
-    Some(integration_field)
+    Some((integration_field, vec![false; dim.max_idx()]))
 }
 
-pub fn calculate_flow_field(dim: &Dimensions, integration_field: &IntegrationField) -> FlowField {
-    use crate::algo::Dir::*;
+/// Flows every tile towards its cheapest neighbor that can be walked to under the neighborhood.
+/// Entry costs, if given, are added to the neighbors' integration, and forbidden entries are never taken.
+/// Tiles without a cheaper neighbor are targets and flow with a zero vector.
+pub fn calculate_flow_field(
+    dim: &Dimensions,
+    neighborhood: Neighborhood,
+    integration_field: &IntegrationField,
+    entry_costs: Option<&EntryCostField>,
+) -> FlowField {
     assert_eq!(
         dim.max_idx(),
         integration_field.len(),
         "Integration field size does not match dimensions!"
     );
-    let integration_at = |x, y, dir: Dir| {
-        let (x_off, y_off) = dir.offset();
-        let (x_next, y_next) = (x + x_off, y + y_off);
-        if dim.in_bounds(x_next, y_next) {
-            integration_field[dim.project_to_field_idx(x_next, y_next)]
+    let integration_at = |x, y| {
+        if dim.in_bounds(x, y) {
+            integration_field[dim.project_to_field_idx(x, y)]
         } else {
             None
         }
     };
 
-    /* in case a diagonal is walked, prevent flowing into impassable corners.
-    Note this does not prevent walking diagonals in situations like: X_
-                                                                     _X
-    but only forces manhattan-like flow in situations like: __
-                                                            _X
-    where _ is a passable tile and X is an impassable tile. */
-    let flow_without_corner_cutting =
-        |(desired, desired_dir): (Option<f32>, Dir),
-         (vertical_neighbor_integration, vertical_neighbor_dir): (Option<f32>, Dir),
-         (horizontal_neighbor_integration, horizontal_neighbor_dir): (Option<f32>, Dir)| {
-            desired.map(|_| {
-                match (
-                    vertical_neighbor_integration,
-                    horizontal_neighbor_integration,
-                ) {
-                    (Some(_), Some(_)) | (None, None) => desired_dir.flow_vec(),
-                    (_, Some(_)) => horizontal_neighbor_dir.flow_vec(),
-                    (Some(_), _) => vertical_neighbor_dir.flow_vec(),
-                }
-            })
-        };
-    let mut flow_field = vec![None;dim.max_idx()];
-    (0..dim.max_idx()).into_par_iter().map(|idx| {
-        let (x,y) = dim.unproject_to_field_coords(idx);
-
-        integration_field[dim.project_to_field_idx(x, y)]?;
-        let c_n = integration_at(x, y, North);
-        let c_ne = integration_at(x, y, NorthEast);
-        let c_e = integration_at(x, y, East);
-        let c_se = integration_at(x, y, SouthEast);
-        let c_s = integration_at(x, y, South);
-        let c_sw = integration_at(x, y, SouthWest);
-        let c_w = integration_at(x, y, West);
-        let c_nw = integration_at(x, y, SouthWest);
-
-        let c = *[c_n, c_ne, c_e, c_se, c_s, c_sw, c_w, c_nw]
-                .iter()
-                .reduce(|last_lowest, i| match (i, last_lowest) {
-                    (Some(c), Some(d)) if c < d => i,
-                    (Some(_), None) => i,
-                    _ => last_lowest,
+    let mut flow_field = vec![None; dim.max_idx()];
+    (0..dim.max_idx())
+        .into_par_iter()
+        .map(|idx| {
+            let (x, y) = dim.unproject_to_field_coords(idx);
+            let own = integration_field[idx]?;
+            /* in case a diagonal is walked, only flow past corners the neighborhood allows. */
+            let passable_at = |(x, y)| integration_at(x, y).is_some();
+            let lowest = neighborhood
+                .steps((x, y), passable_at)
+                .filter_map(|step| {
+                    let (x_next, y_next) = (x + step.offset.0, y + step.offset.1);
+                    let c = integration_at(x_next, y_next)?;
+                    let idx_next = dim.project_to_field_idx(x_next, y_next);
+                    Some((c + entry_cost(entry_costs, idx_next, step.flow_vec)?, step))
                 })
-                .unwrap();
-        /* given a desired flowing-cost, maps to the flow-field vector respecting passability. */
-        let flow = |desired_dir: Dir| c.map(|_| desired_dir.flow_vec());
-        if c == c_n {
-            flow(North)
-        } else if c == c_ne {
-            flow_without_corner_cutting((c_ne, NorthEast), (c_n, North), (c_e, East))
-        } else if c == c_e {
-            flow(East)
-        } else if c == c_se {
-            flow_without_corner_cutting((c_se, SouthEast), (c_s, South), (c_e, East))
-        } else if c == c_s {
-            flow(South)
-        } else if c == c_sw {
-            flow_without_corner_cutting((c_sw, SouthWest), (c_s, South), (c_w, West))
-        } else if c == c_w {
-            flow(West)
-        } else if c == c_nw {
-            flow_without_corner_cutting((c_nw, NorthWest), (c_n, North), (c_w, West))
+                .reduce(|lowest, next| if next.0 < lowest.0 { next } else { lowest });
+            match lowest {
+                Some((c, step)) if c < own => Some(step.flow_vec),
+                /* tiles without a cheaper neighbor are targets, agents arriving there come to a halt. */
+                _ => Some((0., 0.)),
+            }
+        })
+        .collect_into_vec(&mut flow_field);
+    flow_field
+}
+
+/// Alternative to the octile flow field, following the gradient of the integration field.
+/// Directions are continuous, so crowds spread naturally instead of lining up along eight axes.
+/// Uses central differences, or one-sided differences next to impassable tiles, never flowing into them.
+/// Where the gradient vanishes or leads into an impassable tile, the octile direction is kept.
+pub fn calculate_gradient_flow_field(
+    dim: &Dimensions,
+    neighborhood: Neighborhood,
+    integration_field: &IntegrationField,
+) -> FlowField {
+    let octile_field = calculate_flow_field(dim, neighborhood, integration_field, None);
+    let integration_at = |x, y| {
+        if dim.in_bounds(x, y) {
+            integration_field[dim.project_to_field_idx(x, y)]
+        } else {
+            None
+        }
+    };
+    /* derivative along one axis, given the integration behind, at and ahead of the tile. */
+    let derivative = |behind: Option<f32>, own: f32, ahead: Option<f32>| match (behind, ahead) {
+        (Some(b), Some(a)) => (a - b) / 2.,
+        (Some(b), None) => (own - b).max(0.),
+        (None, Some(a)) => (a - own).min(0.),
+        (None, None) => 0.,
+    };
+    let mut flow_field = vec![None; dim.max_idx()];
+    (0..dim.max_idx())
+        .into_par_iter()
+        .map(|idx| {
+            let own = integration_field[idx]?;
+            let octile = octile_field[idx];
+            let (x, y) = dim.unproject_to_field_coords(idx);
+            let gx = derivative(integration_at(x - 1, y), own, integration_at(x + 1, y));
+            let gy = derivative(integration_at(x, y - 1), own, integration_at(x, y + 1));
+            let len = gx.hypot(gy);
+            if len <= f32::EPSILON {
+                return octile;
+            }
+            let (vx, vy) = (-gx / len, -gy / len);
+            if integration_at(x + vx.round() as isize, y + vy.round() as isize).is_none() {
+                return octile;
+            }
+            Some((vx, vy))
+        })
+        .collect_into_vec(&mut flow_field);
+    flow_field
+}
+
+/// Bilinearly blends the flow vectors of the four tiles surrounding a continuous map position.
+/// Tile centers lie at half coordinates, impassable or out of bounds tiles don't contribute.
+/// Returns `None` if the tile at the position itself can not flow, and a normalized vector otherwise.
+pub fn sample_flow_field(
+    dim: &Dimensions,
+    flow_field: &FlowField,
+    (px, py): Vector2D,
+) -> Option<Vector2D> {
+    let flow_at = |x: isize, y: isize| {
+        if dim.in_bounds(x, y) {
+            flow_field[dim.project_to_field_idx(x, y)]
+        } else {
+            None
+        }
+    };
+    flow_at(px.floor() as isize, py.floor() as isize)?;
+
+    let (u, v) = (px - 0.5, py - 0.5);
+    let (x0, y0) = (u.floor(), v.floor());
+    let (fx, fy) = (u - x0, v - y0);
+    let (x0, y0) = (x0 as isize, y0 as isize);
+    let (mut vx, mut vy) = (0., 0.);
+    for (x, y, weight) in [
+        (x0, y0, (1. - fx) * (1. - fy)),
+        (x0 + 1, y0, fx * (1. - fy)),
+        (x0, y0 + 1, (1. - fx) * fy),
+        (x0 + 1, y0 + 1, fx * fy),
+    ] {
+        if let Some((fvx, fvy)) = flow_at(x, y) {
+            vx += weight * fvx;
+            vy += weight * fvy;
+        }
+    }
+    let len = vx.hypot(vy);
+    if len <= f32::EPSILON {
+        Some((0., 0.))
+    } else {
+        Some((vx / len, vy / len))
+    }
+}
+
+/// Nearest tile within `radius` of the tile that is accepted, the tile itself included.
+/// Distances are measured between the tiles' centers, ties go to the first tile row by row.
+pub fn snap_to_nearest(
+    dim: &Dimensions,
+    neighborhood: Neighborhood,
+    from @ (from_x, from_y): Coord,
+    radius: usize,
+    accept: impl Fn(usize) -> bool,
+) -> Option<Coord> {
+    let (cx, cy) = neighborhood.center(from);
+    /* hex rows or columns lie closer together, so a radius covers a few more of them. */
+    let reach = (radius as f32 / HEX_HEIGHT).ceil() as isize;
+    /* only tiles of the field are looked at, which keeps far-off tiles from overflowing, too. */
+    let (min_x, max_x) = (
+        from_x.saturating_sub(reach).max(0),
+        from_x.saturating_add(reach).min(dim.width() as isize - 1),
+    );
+    let (min_y, max_y) = (
+        from_y.saturating_sub(reach).max(0),
+        from_y.saturating_add(reach).min(dim.height() as isize - 1),
+    );
+    let mut nearest: Option<(f32, Coord)> = None;
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            if !accept(dim.project_to_field_idx(x, y)) {
+                continue;
+            }
+            let (px, py) = neighborhood.center((x, y));
+            let distance = (px - cx).hypot(py - cy);
+            if distance > radius as f32 + 1e-3 {
+                continue;
+            }
+            if !matches!(nearest, Some((nearest, _)) if nearest <= distance) {
+                nearest = Some((distance, (x, y)));
+            }
+        }
+    }
+    nearest.map(|(_, tile)| tile)
+}
+
+/// Flow of the tile, or if it can't flow, the direction towards the nearest tile within `radius`
+/// that can, so agents pushed onto impassable tiles find their way back.
+pub fn snap_flow(
+    dim: &Dimensions,
+    neighborhood: Neighborhood,
+    flow_field: &FlowField,
+    from: Coord,
+    radius: usize,
+) -> Option<Vector2D> {
+    let (x, y) = snap_to_nearest(dim, neighborhood, from, radius, |idx| {
+        flow_field[idx].is_some()
+    })?;
+    if (x, y) == from {
+        return flow_field[dim.project_to_field_idx(x, y)];
+    }
+    Some(direction_to(neighborhood, from, (x, y)))
+}
+
+/// Normalized direction from the center of one tile towards the center of another.
+pub fn direction_to(neighborhood: Neighborhood, from: Coord, to: Coord) -> Vector2D {
+    let ((fx, fy), (tx, ty)) = (neighborhood.center(from), neighborhood.center(to));
+    let (dx, dy) = (tx - fx, ty - fy);
+    let len = dx.hypot(dy);
+    (dx / len, dy / len)
+}
+
+/// Labels the passable tiles with the id of their region, the tiles connected under the neighborhood.
+/// Regions are numbered in the order of their first tile, row by row.
+/// Directional entry costs are not considered, one-way tiles may connect regions in one direction only.
+pub fn calculate_regions(
+    dim: &Dimensions,
+    neighborhood: Neighborhood,
+    cost_field: &CostField,
+) -> RegionField {
+    assert_eq!(
+        dim.max_idx(),
+        cost_field.len(),
+        "Cost field size does not match dimensions!"
+    );
+    let passable_at =
+        |(x, y): Coord| dim.in_bounds(x, y) && cost_field[dim.project_to_field_idx(x, y)].is_some();
+    let mut regions: RegionField = vec![None; dim.max_idx()];
+    let mut next_region = 0;
+    let mut stack = vec![];
+    for start in 0..dim.max_idx() {
+        if cost_field[start].is_none() || regions[start].is_some() {
+            continue;
+        }
+        regions[start] = Some(next_region);
+        stack.push(start);
+        while let Some(idx) = stack.pop() {
+            let (x, y) = dim.unproject_to_field_coords(idx);
+            for step in neighborhood.steps((x, y), passable_at) {
+                let next @ (x_next, y_next) = (x + step.offset.0, y + step.offset.1);
+                if !passable_at(next) {
+                    continue;
+                }
+                let idx_next = dim.project_to_field_idx(x_next, y_next);
+                if regions[idx_next].is_none() {
+                    regions[idx_next] = Some(next_region);
+                    stack.push(idx_next);
+                }
+            }
+        }
+        next_region += 1;
+    }
+    regions
+}
+
+/// Follows the flow field tile by tile, always stepping to the neighbor best matching the flow vector.
+/// Ends at a target, before a tile that can't flow, before visiting a tile twice or after `max_steps`.
+/// Returns the visited tiles including the first one, none if that one can't flow.
+pub fn trace_flow_field(
+    dim: &Dimensions,
+    neighborhood: Neighborhood,
+    flow_field: &FlowField,
+    from: Coord,
+    max_steps: usize,
+) -> Vec<Coord> {
+    let flow_at = |(x, y): Coord| {
+        if dim.in_bounds(x, y) {
+            flow_field[dim.project_to_field_idx(x, y)]
         } else {
-            unreachable!()
+            None
+        }
+    };
+    let mut path = vec![];
+    let mut visited = HashSet::new();
+    let mut current = from;
+    while let Some((vx, vy)) = flow_at(current) {
+        path.push(current);
+        visited.insert(current);
+        if path.len() > max_steps || (vx == 0. && vy == 0.) {
+            break;
+        }
+        let (x, y) = current;
+        /* gradient and line of sight flow may point in between neighbors. */
+        let next = neighborhood
+            .steps(current, |c| flow_at(c).is_some())
+            .map(|step| {
+                let (sx, sy) = step.flow_vec;
+                ((x + step.offset.0, y + step.offset.1), sx * vx + sy * vy)
+            })
+            .filter(|&(next, alignment)| alignment > 0. && flow_at(next).is_some())
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        match next {
+            Some((next, _)) if !visited.contains(&next) => current = next,
+            _ => break,
+        }
+    }
+    path
+}
+
+/// Splats agents onto the tiles around their continuous map positions, continuum crowds style.
+/// Every agent adds a density of one, spread bilinearly over the four tiles whose centers surround it.
+/// Shares falling outside of the map are dropped.
+pub fn calculate_density_field(dim: &Dimensions, positions: &[Vector2D]) -> Vec<f32> {
+    /* agents binned by the row of tile centers above them, so rows can be filled in parallel. */
+    let mut bins: Vec<Vec<(f32, f32)>> = vec![vec![]; dim.height() + 1];
+    for &(px, py) in positions {
+        let (u, v) = (px - 0.5, py - 0.5);
+        let y0 = v.floor();
+        if u.is_finite() && y0 >= -1. && y0 < dim.height() as f32 {
+            bins[(y0 + 1.) as usize].push((u, v - y0));
+        }
+    }
+    let mut density = vec![0.; dim.max_idx()];
+    density
+        .par_chunks_mut(dim.width())
+        .enumerate()
+        .for_each(|(y, row)| {
+            let from_above = bins[y].iter().copied();
+            let from_below = bins[y + 1].iter().map(|&(u, fy)| (u, 1. - fy));
+            for (u, weight_y) in from_above.chain(from_below) {
+                let x0 = u.floor();
+                let fx = u - x0;
+                for (x, weight_x) in [(x0, 1. - fx), (x0 + 1., fx)] {
+                    if x >= 0. && x < dim.width() as f32 {
+                        row[x as usize] += weight_x * weight_y;
+                    }
+                }
+            }
+        });
+    density
+}
+
+/// Walks the supercover line between the two tile centers.
+/// Where the line passes exactly through a corner, both tiles adjacent to it must be passable as well.
+fn is_visible(dim: &Dimensions, cost_field: &CostField, (x0, y0): Coord, (x1, y1): Coord) -> bool {
+    let passable_at =
+        |x, y| dim.in_bounds(x, y) && cost_field[dim.project_to_field_idx(x, y)].is_some();
+    let (dx, dy) = ((x1 - x0).abs(), (y1 - y0).abs());
+    let (x_inc, y_inc) = ((x1 - x0).signum(), (y1 - y0).signum());
+    let (mut x, mut y) = (x0, y0);
+    let mut error = dx - dy;
+    let mut n = 1 + dx + dy;
+    while n > 0 {
+        if !passable_at(x, y) {
+            return false;
+        }
+        match error.cmp(&0) {
+            Ordering::Greater => {
+                x += x_inc;
+                error -= 2 * dy;
+            }
+            Ordering::Less => {
+                y += y_inc;
+                error += 2 * dx;
+            }
+            Ordering::Equal => {
+                if !passable_at(x + x_inc, y) || !passable_at(x, y + y_inc) {
+                    return false;
+                }
+                x += x_inc;
+                y += y_inc;
+                error += 2 * (dx - dy);
+                n -= 1;
+            }
         }
-    }).collect_into_vec(&mut flow_field);
+        n -= 1;
+    }
+    true
+}
+
+/// Line-of-sight pass over a calculated flow field.
+/// Tiles that see one of their targets unobstructed flow straight towards it,
+/// all other tiles keep their octile direction.
+/// Costs of the tiles along the line are not considered, this is meant for open ground.
+pub fn apply_line_of_sight(
+    dim: &Dimensions,
+    cost_field: &CostField,
+    targets: &[Target],
+    integration_field: &IntegrationField,
+    flow_field: &mut FlowField,
+) {
+    assert_eq!(
+        dim.max_idx(),
+        flow_field.len(),
+        "Flow field size does not match dimensions!"
+    );
+    let targets: Vec<Target> = targets
+        .iter()
+        .copied()
+        .filter(|&((x, y), _)| {
+            dim.in_bounds(x, y) && cost_field[dim.project_to_field_idx(x, y)].is_some()
+        })
+        .collect();
     flow_field
+        .par_iter_mut()
+        .enumerate()
+        .for_each(|(idx, flow)| match (*flow, integration_field[idx]) {
+            (Some((vx, vy)), Some(integration)) if vx != 0. || vy != 0. => {
+                let (x, y) = dim.unproject_to_field_coords(idx);
+                /* a target farther away than the tile's integration can not be the one it is heading to. */
+                let visible_target = targets
+                    .iter()
+                    .filter_map(|&((to_x, to_y), initial_cost)| {
+                        let (dx, dy) = ((to_x - x) as f32, (to_y - y) as f32);
+                        let estimate = initial_cost + dx.hypot(dy);
+                        (estimate <= integration + f32::EPSILON * integration.abs().max(1.))
+                            .then_some((estimate, (dx, dy), (to_x, to_y)))
+                    })
+                    .filter(|&(_, _, to)| is_visible(dim, cost_field, (x, y), to))
+                    .min_by(|(a, _, _), (b, _, _)| a.total_cmp(b));
+                if let Some((_, (dx, dy), _)) = visible_target {
+                    let len = dx.hypot(dy);
+                    *flow = Some((dx / len, dy / len));
+                }
+            }
+            _ => (),
+        });
 }
 
 #[cfg(test)]
 mod test {
     use crate::algo::*;
-    use std::f32::consts::SQRT_2;
+    use std::f32::consts::{FRAC_1_SQRT_2, SQRT_2};
 
     #[test]
     fn small_integration_field() {
         let cost_field = vec![Some(0.); 9];
-        let integration_field =
-            crate::algo::calculate_integration_field(&Dimensions::new(3, 3), (1, 1), &cost_field);
+        let integration_field = crate::algo::calculate_integration_field(
+            &Dimensions::new(3, 3),
+            Neighborhood::Eight,
+            &[((1, 1), 0.)],
+            &cost_field,
+            None,
+        );
         assert_eq!(
             integration_field,
             Some(vec![
@@ -300,4 +1417,668 @@ mod test {
             "integration field doesn't match."
         )
     }
+
+    #[test]
+    fn integration_field_routes_around_expensive_tiles() {
+        let mut cost_field = vec![Some(0.); 9];
+        cost_field[4] = Some(10.);
+        let integration_field = crate::algo::calculate_integration_field(
+            &Dimensions::new(3, 3),
+            Neighborhood::Eight,
+            &[((0, 0), 0.)],
+            &cost_field,
+            None,
+        )
+        .unwrap();
+        let expected = [
+            0.,
+            1.,
+            2.,
+            1.,
+            10. + SQRT_2,
+            1. + SQRT_2,
+            2.,
+            1. + SQRT_2,
+            2. + SQRT_2,
+        ];
+        for (idx, (actual, expected)) in integration_field.iter().zip(expected).enumerate() {
+            assert!(
+                (actual.unwrap() - expected).abs() < 1e-5,
+                "integration at {} is {:?}, expected {}",
+                idx,
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn integration_field_towards_nearest_target() {
+        let cost_field = vec![Some(0.); 5];
+        let integration_field = crate::algo::calculate_integration_field(
+            &Dimensions::new(5, 1),
+            Neighborhood::Eight,
+            &[((0, 0), 0.), ((4, 0), 0.), ((-1, 0), 0.)],
+            &cost_field,
+            None,
+        );
+        assert_eq!(
+            integration_field,
+            Some(vec![Some(0.), Some(1.), Some(2.), Some(1.), Some(0.)]),
+            "integration field doesn't match."
+        )
+    }
+
+    #[test]
+    fn integration_field_respects_initial_target_cost() {
+        let cost_field = vec![Some(0.); 5];
+        let integration_field = crate::algo::calculate_integration_field(
+            &Dimensions::new(5, 1),
+            Neighborhood::Eight,
+            &[((0, 0), 3.), ((4, 0), 0.), ((4, 0), 1.)],
+            &cost_field,
+            None,
+        );
+        assert_eq!(
+            integration_field,
+            Some(vec![Some(3.), Some(3.), Some(2.), Some(1.), Some(0.)]),
+            "integration field doesn't match."
+        )
+    }
+
+    #[test]
+    fn neighborhood_controls_diagonals() {
+        let dim = Dimensions::new(2, 2);
+        let cost_field = vec![Some(0.), None, Some(0.), Some(0.)];
+        for (neighborhood, expected_cost, expected_flow) in [
+            (Neighborhood::Four, 2., (-1., 0.)),
+            (Neighborhood::Eight, 2., (-1., 0.)),
+            (
+                Neighborhood::EightCornerCutting,
+                SQRT_2,
+                (-FRAC_1_SQRT_2, -FRAC_1_SQRT_2),
+            ),
+        ] {
+            let integration_field = crate::algo::calculate_integration_field(
+                &dim,
+                neighborhood,
+                &[((0, 0), 0.)],
+                &cost_field,
+                None,
+            )
+            .unwrap();
+            assert_eq!(
+                integration_field[3],
+                Some(expected_cost),
+                "integration across the corner doesn't match."
+            );
+            assert_eq!(
+                crate::algo::calculate_flow_field(&dim, neighborhood, &integration_field, None)[3],
+                Some(expected_flow),
+                "flow across the corner doesn't match."
+            );
+        }
+    }
+
+    #[test]
+    fn clearance_blocks_narrow_corridors() {
+        // two open rooms joined by a corridor one tile wide.
+        let dim = Dimensions::new(5, 3);
+        let cost_field: CostField = (0..15)
+            .map(|idx| {
+                if idx == 2 || idx == 12 {
+                    None
+                } else {
+                    Some(0.)
+                }
+            })
+            .collect();
+        assert_eq!(
+            crate::algo::calculate_clearance(&dim, &cost_field),
+            vec![2, 1, 0, 2, 1, 2, 1, 1, 2, 1, 1, 1, 0, 1, 1],
+            "clearance doesn't match."
+        );
+        let restricted = crate::algo::restrict_to_clearance(&dim, cost_field.clone(), 2);
+        let integration_field = crate::algo::calculate_integration_field(
+            &dim,
+            Neighborhood::Eight,
+            &[((3, 0), 0.)],
+            &restricted,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            integration_field[0], None,
+            "large unit passes the corridor."
+        );
+        assert_eq!(
+            crate::algo::restrict_to_clearance(&dim, cost_field.clone(), 1),
+            cost_field,
+            "single tile units are restricted."
+        );
+    }
+
+    #[test]
+    fn flow_field_respects_entry_costs() {
+        let dim = Dimensions::new(3, 2);
+        let cost_field = vec![Some(0.); 6];
+        // the middle of the top row can not be entered moving west.
+        let mut entry_costs: EntryCostField = vec![None; 6];
+        entry_costs[1] = Some([Some(0.), Some(0.), Some(0.), None]);
+        let integration_field = crate::algo::calculate_integration_field(
+            &dim,
+            Neighborhood::Eight,
+            &[((0, 0), 0.)],
+            &cost_field,
+            Some(&entry_costs),
+        )
+        .unwrap();
+        assert_eq!(
+            integration_field,
+            vec![
+                Some(0.),
+                Some(1.),
+                Some(2. * SQRT_2),
+                Some(1.),
+                Some(SQRT_2),
+                Some(SQRT_2 + 1.)
+            ],
+            "integration around the one-way tile doesn't match."
+        );
+        let flow_field = crate::algo::calculate_flow_field(
+            &dim,
+            Neighborhood::Eight,
+            &integration_field,
+            Some(&entry_costs),
+        );
+        assert_eq!(
+            flow_field[2],
+            Some((-FRAC_1_SQRT_2, FRAC_1_SQRT_2)),
+            "flow enters the one-way tile the forbidden way."
+        );
+    }
+
+    #[test]
+    fn density_field_splats_agents_bilinearly() {
+        let dim = Dimensions::new(3, 2);
+        let density = crate::algo::calculate_density_field(&dim, &[(0.5, 0.5), (2., 1.), (3., 1.)]);
+        assert_eq!(
+            density,
+            vec![1., 0.25, 0.5, 0., 0.25, 0.5],
+            "density doesn't match."
+        );
+    }
+
+    #[test]
+    fn integration_field_stops_at_max_cost() {
+        let dim = Dimensions::new(6, 1);
+        let cost_field = vec![Some(0.), Some(0.), Some(0.), Some(0.), Some(0.), None];
+        let within = |max_cost| {
+            crate::algo::calculate_integration_field_within(
+                &dim,
+                Neighborhood::Eight,
+                &[((0, 0), 0.)],
+                &cost_field,
+                None,
+                max_cost,
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            within(2.5),
+            (
+                vec![Some(0.), Some(1.), Some(2.), None, None, None],
+                vec![false, false, false, true, true, false]
+            ),
+            "integration beyond the maximum cost doesn't match."
+        );
+        assert_eq!(
+            within(4.),
+            (
+                vec![Some(0.), Some(1.), Some(2.), Some(3.), Some(4.), None],
+                vec![false; 6]
+            ),
+            "integration within the maximum cost doesn't match."
+        );
+    }
+
+    #[test]
+    fn eikonal_integration_field_stops_at_max_cost() {
+        let dim = Dimensions::new(4, 4);
+        let cost_field = vec![Some(0.); 16];
+        let targets = [((0, 0), 0.)];
+        let full = calculate_eikonal_integration_field(&dim, &targets, &cost_field).unwrap();
+        let (within, beyond) =
+            calculate_eikonal_integration_field_within(&dim, &targets, &cost_field, 2.5).unwrap();
+        assert_eq!(
+            beyond,
+            full.iter()
+                .map(|c| matches!(c, Some(c) if *c > 2.5))
+                .collect::<RangeField>(),
+            "tiles beyond the maximum cost don't match."
+        );
+        assert_eq!(
+            within,
+            full.iter()
+                .map(|c| c.filter(|&c| c <= 2.5))
+                .collect::<IntegrationField>(),
+            "integration beyond the maximum cost doesn't match."
+        );
+    }
+
+    #[test]
+    fn flee_field_runs_past_threat_towards_safety() {
+        let dim = Dimensions::new(7, 1);
+        let cost_field = vec![Some(0.); 7];
+        let flee = |safety_distance| {
+            let threats = [((1, 0), 0.)];
+            let towards_threats =
+                calculate_integration_field(&dim, Neighborhood::Four, &threats, &cost_field, None)
+                    .unwrap();
+            let targets = flee_targets(&dim, &cost_field, &towards_threats, safety_distance);
+            let integration_field =
+                calculate_integration_field(&dim, Neighborhood::Four, &targets, &cost_field, None)
+                    .unwrap();
+            calculate_flow_field(&dim, Neighborhood::Four, &integration_field, None)
+        };
+        let (stay, east) = (Some((0., 0.)), Some((1., 0.)));
+        assert_eq!(
+            flee(f32::INFINITY),
+            vec![stay, east, east, east, east, east, stay],
+            "flee field doesn't match."
+        );
+        assert_eq!(
+            flee(2.),
+            vec![stay, east, east, stay, stay, stay, stay],
+            "flee field within the safety distance doesn't match."
+        );
+    }
+
+    #[test]
+    fn trace_follows_flow_field_to_target() {
+        let dim = Dimensions::new(3, 3);
+        let cost_field = vec![Some(0.); 9];
+        let integration_field = calculate_integration_field(
+            &dim,
+            Neighborhood::Eight,
+            &[((0, 0), 0.)],
+            &cost_field,
+            None,
+        )
+        .unwrap();
+        let flow_field = calculate_flow_field(&dim, Neighborhood::Eight, &integration_field, None);
+        assert_eq!(
+            trace_flow_field(&dim, Neighborhood::Eight, &flow_field, (2, 1), usize::MAX),
+            vec![(2, 1), (1, 0), (0, 0)],
+            "traced path doesn't match."
+        );
+        assert_eq!(
+            trace_flow_field(&dim, Neighborhood::Eight, &flow_field, (2, 1), 1),
+            vec![(2, 1), (1, 0)],
+            "traced path limited to one step doesn't match."
+        );
+        assert_eq!(
+            trace_flow_field(&dim, Neighborhood::Eight, &flow_field, (3, 1), usize::MAX),
+            vec![],
+            "traced path out of bounds doesn't match."
+        );
+        let looping = vec![Some((1., 0.)), Some((-1., 0.)), None];
+        assert_eq!(
+            trace_flow_field(
+                &Dimensions::new(3, 1),
+                Neighborhood::Four,
+                &looping,
+                (0, 0),
+                usize::MAX
+            ),
+            vec![(0, 0), (1, 0)],
+            "traced path of a loop doesn't match."
+        );
+    }
+
+    #[test]
+    fn regions_follow_neighborhood() {
+        let dim = Dimensions::new(3, 2);
+        let cost_field = vec![None, Some(0.), None, Some(0.), None, Some(0.)];
+        assert_eq!(
+            calculate_regions(&dim, Neighborhood::Eight, &cost_field),
+            vec![None, Some(0), None, Some(1), None, Some(2)],
+            "regions of square tiles don't match."
+        );
+        assert_eq!(
+            calculate_regions(&dim, Neighborhood::HexOddRows, &cost_field),
+            vec![None, Some(0), None, Some(0), None, Some(1)],
+            "regions of hexagonal tiles don't match."
+        );
+    }
+
+    #[test]
+    fn snapping_finds_nearest_tile() {
+        let dim = Dimensions::new(4, 3);
+        #[rustfmt::skip]
+        let flow_field = vec![
+            None, None, None, Some((1., 0.)),
+            None, None, None, None,
+            Some((0., -1.)), None, None, None,
+        ];
+        let snap = |from, radius| {
+            snap_to_nearest(&dim, Neighborhood::Eight, from, radius, |idx| {
+                flow_field[idx].is_some()
+            })
+        };
+        assert_eq!(
+            snap((1, 1), 1),
+            None,
+            "snapping beyond radius doesn't match."
+        );
+        assert_eq!(snap((1, 1), 2), Some((0, 2)), "snapping doesn't match.");
+        assert_eq!(
+            snap((-2, 0), 2),
+            None,
+            "snapping from out of bounds doesn't match."
+        );
+        assert_eq!(
+            snap((isize::MIN, isize::MAX), 2),
+            None,
+            "snapping from far off doesn't match."
+        );
+        assert_eq!(
+            snap_flow(&dim, Neighborhood::Eight, &flow_field, (2, 0), 1),
+            Some((1., 0.)),
+            "flow towards the snapped tile doesn't match."
+        );
+        assert_eq!(
+            snap_flow(&dim, Neighborhood::Eight, &flow_field, (3, 0), 1),
+            Some((1., 0.)),
+            "flow of a tile that can flow doesn't match."
+        );
+        assert_eq!(
+            Neighborhood::HexOddRows.center((1, 1)),
+            (1.5, HEX_HEIGHT),
+            "center of a shifted hexagon doesn't match."
+        );
+    }
+
+    #[test]
+    fn dimensions_convert_cells_by_origin() {
+        let dim = Dimensions::new(4, 3).with_origin((-2, 5));
+        assert_eq!(
+            dim.cell_to_local((-2, 5)),
+            (0, 0),
+            "local tile doesn't match."
+        );
+        assert_eq!(dim.local_to_cell((3, 2)), (1, 7), "cell doesn't match.");
+        assert_eq!(
+            dim.pos_to_local((-1.5, 5.25)),
+            (0.5, 0.25),
+            "local position doesn't match."
+        );
+        let (x, y) = dim.cell_to_local((isize::MIN, isize::MAX));
+        assert!(!dim.in_bounds(x, y), "far-off cell is in bounds.");
+    }
+
+    #[test]
+    fn hex_neighborhood_follows_odd_origin() {
+        let dim = Dimensions::new(3, 3).with_origin((0, 1));
+        let cost_field = vec![Some(0.); 9];
+        let integration_field = crate::algo::calculate_integration_field(
+            &dim,
+            Neighborhood::HexOddRows.translated(dim.origin()),
+            &[(dim.cell_to_local((0, 2)), 0.)],
+            &cost_field,
+            None,
+        )
+        .unwrap();
+        let full_dim = Dimensions::new(3, 4);
+        let full_integration_field = crate::algo::calculate_integration_field(
+            &full_dim,
+            Neighborhood::HexOddRows,
+            &[((0, 2), 0.)],
+            &vec![Some(0.); 12],
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            integration_field,
+            full_integration_field[3..],
+            "integration field doesn't match the one in map coordinates."
+        );
+        assert_eq!(
+            integration_field,
+            [1., 2., 3., 0., 1., 2., 1., 2., 3.].map(Some),
+            "integration field doesn't match."
+        );
+    }
+
+    #[test]
+    fn hex_integration_and_flow_field() {
+        let dim = Dimensions::new(3, 3);
+        let cost_field = vec![Some(0.); 9];
+        let integration_field = crate::algo::calculate_integration_field(
+            &dim,
+            Neighborhood::HexOddRows,
+            &[((1, 1), 0.)],
+            &cost_field,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            integration_field,
+            vec![
+                Some(2.),
+                Some(1.),
+                Some(1.),
+                Some(1.),
+                Some(0.),
+                Some(1.),
+                Some(2.),
+                Some(1.),
+                Some(1.)
+            ],
+            "integration field doesn't match."
+        );
+        let flow_field = crate::algo::calculate_flow_field(
+            &dim,
+            Neighborhood::HexOddRows,
+            &integration_field,
+            None,
+        );
+        assert_eq!(
+            flow_field[dim.project_to_field_idx(0, 0)],
+            Some((1., 0.)),
+            "flow along the row doesn't match."
+        );
+        let (vx, vy) = flow_field[dim.project_to_field_idx(1, 2)].unwrap();
+        assert!(
+            (vx - 0.5).abs() < 1e-6 && (vy + 3f32.sqrt() / 2.).abs() < 1e-6,
+            "flow into the shifted row doesn't match."
+        );
+        let (cx, cy) = Neighborhood::HexOddRows.cell_vec((vx, vy));
+        assert!(
+            (cx - 0.5).abs() < 1e-6 && (cy + 1.).abs() < 1e-6,
+            "flow in cell units doesn't match."
+        )
+    }
+
+    #[test]
+    fn flow_field_halts_on_targets() {
+        let dim = Dimensions::new(4, 1);
+        let cost_field = vec![Some(0.); 4];
+        let integration_field = crate::algo::calculate_integration_field(
+            &dim,
+            Neighborhood::Eight,
+            &[((0, 0), 0.), ((1, 0), 0.)],
+            &cost_field,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            crate::algo::calculate_flow_field(&dim, Neighborhood::Eight, &integration_field, None),
+            vec![
+                Some((0., 0.)),
+                Some((0., 0.)),
+                Some((-1., 0.)),
+                Some((-1., 0.))
+            ],
+            "flow field doesn't match."
+        )
+    }
+
+    #[test]
+    fn line_of_sight_flows_straight_to_visible_target() {
+        let dim = Dimensions::new(4, 3);
+        let mut cost_field = vec![Some(0.); 12];
+        cost_field[dim.project_to_field_idx(1, 0)] = None;
+        let targets = [((3, 2), 0.)];
+        let integration_field = crate::algo::calculate_integration_field(
+            &dim,
+            Neighborhood::Eight,
+            &targets,
+            &cost_field,
+            None,
+        )
+        .unwrap();
+        let mut flow_field =
+            crate::algo::calculate_flow_field(&dim, Neighborhood::Eight, &integration_field, None);
+        crate::algo::apply_line_of_sight(
+            &dim,
+            &cost_field,
+            &targets,
+            &integration_field,
+            &mut flow_field,
+        );
+        let (vx, vy) = flow_field[dim.project_to_field_idx(0, 1)].unwrap();
+        assert!(
+            (vx - 3. / 10f32.sqrt()).abs() < 1e-6 && (vy - 1. / 10f32.sqrt()).abs() < 1e-6,
+            "visible tile doesn't flow straight to the target."
+        );
+        assert_eq!(
+            flow_field[dim.project_to_field_idx(0, 0)],
+            Some((0., 1.)),
+            "obstructed tile doesn't keep its octile direction."
+        )
+    }
+
+    #[test]
+    fn gradient_flow_field_does_not_flow_into_walls() {
+        let dim = Dimensions::new(3, 3);
+        let mut cost_field = vec![Some(0.); 9];
+        cost_field[dim.project_to_field_idx(1, 1)] = None;
+        let integration_field = crate::algo::calculate_integration_field(
+            &dim,
+            Neighborhood::Eight,
+            &[((2, 2), 0.)],
+            &cost_field,
+            None,
+        )
+        .unwrap();
+        let flow_field = crate::algo::calculate_gradient_flow_field(
+            &dim,
+            Neighborhood::Eight,
+            &integration_field,
+        );
+        assert_eq!(
+            flow_field[dim.project_to_field_idx(1, 0)],
+            Some((1., 0.)),
+            "tile above the wall doesn't flow alongside it."
+        );
+        let (vx, vy) = flow_field[dim.project_to_field_idx(2, 0)].unwrap();
+        assert!(
+            vx.abs() < 1e-6 && (vy - 1.).abs() < 1e-6,
+            "corner tile doesn't flow towards the target."
+        );
+        assert_eq!(
+            flow_field[dim.project_to_field_idx(2, 2)],
+            Some((0., 0.)),
+            "target doesn't halt."
+        )
+    }
+
+    #[test]
+    fn sampling_blends_neighboring_tiles() {
+        let dim = Dimensions::new(2, 2);
+        let flow_field = vec![Some((1., 0.)), Some((0., 1.)), None, Some((0., 1.))];
+        let (vx, vy) = crate::algo::sample_flow_field(&dim, &flow_field, (1., 0.5)).unwrap();
+        assert!(
+            (vx - FRAC_1_SQRT_2).abs() < 1e-6 && (vy - FRAC_1_SQRT_2).abs() < 1e-6,
+            "vectors at the tile border aren't blended."
+        );
+        assert_eq!(
+            crate::algo::sample_flow_field(&dim, &flow_field, (0.5, 1.5)),
+            None,
+            "impassable tile is sampled."
+        );
+        assert_eq!(
+            crate::algo::sample_flow_field(&dim, &flow_field, (0.5, 0.9)),
+            Some((1., 0.)),
+            "impassable neighbor contributes."
+        )
+    }
+
+    #[test]
+    fn small_eikonal_integration_field() {
+        let cost_field = vec![Some(0.); 9];
+        let integration_field = crate::algo::calculate_eikonal_integration_field(
+            &Dimensions::new(3, 3),
+            &[((1, 1), 0.)],
+            &cost_field,
+        );
+        let d = 1. + 0.5 * SQRT_2;
+        assert_eq!(
+            integration_field,
+            Some(vec![
+                Some(d),
+                Some(1.),
+                Some(d),
+                Some(1.),
+                Some(0.),
+                Some(1.),
+                Some(d),
+                Some(1.),
+                Some(d)
+            ]),
+            "integration field doesn't match."
+        )
+    }
+
+    #[test]
+    fn repaired_integration_field_matches_recalculation() {
+        let dim = Dimensions::new(6, 5);
+        let mut cost_field: CostField = (0..30).map(|i| Some((i % 3) as f32 * 0.5)).collect();
+        cost_field[dim.project_to_field_idx(3, 1)] = None;
+        let targets = [((0, 2), 0.), ((5, 4), 2.)];
+        let mut integration_field = crate::algo::calculate_integration_field(
+            &dim,
+            Neighborhood::Eight,
+            &targets,
+            &cost_field,
+            None,
+        )
+        .unwrap();
+
+        let changed = [(2, 2), (3, 1), (4, 4)];
+        cost_field[dim.project_to_field_idx(2, 2)] = None;
+        cost_field[dim.project_to_field_idx(3, 1)] = Some(0.);
+        cost_field[dim.project_to_field_idx(4, 4)] = Some(3.);
+        crate::algo::repair_integration_field(
+            &dim,
+            Neighborhood::Eight,
+            &targets,
+            &cost_field,
+            None,
+            &mut integration_field,
+            &changed,
+        );
+        assert_eq!(
+            Some(integration_field),
+            crate::algo::calculate_integration_field(
+                &dim,
+                Neighborhood::Eight,
+                &targets,
+                &cost_field,
+                None
+            ),
+            "repaired integration field doesn't match."
+        )
+    }
 }
@@ -2,12 +2,38 @@ use gdnative::api::Resource;
 use gdnative::prelude::*;
 
 use crate::algo::{self, Dimensions};
+use crate::generator::IntegrationMethod;
+use crate::sectors::SectorFlow;
 
-fn round_vec(v: Vector2) -> Result<(isize, isize), GodotString> {
+pub(crate) fn finite_vec(v: Vector2) -> Result<algo::Vector2D, GodotString> {
     if v.x.is_nan() || v.x.is_infinite() || v.y.is_nan() || v.y.is_infinite() {
         Err(format!("Bad vector access: {}", v.to_variant()).into())
     } else {
-        Ok((v.x as isize, v.y as isize))
+        Ok((v.x, v.y))
+    }
+}
+
+pub(crate) fn round_vec(v: Vector2) -> Result<(isize, isize), GodotString> {
+    finite_vec(v).map(|(x, y)| (x.floor() as isize, y.floor() as isize))
+}
+
+/// Converts a flow vector for output, into the TileMap's local space if `world_space` is set.
+/// World-space vectors are normalized again, since cells need not be square.
+fn output_vec(
+    (vx, vy): algo::Vector2D,
+    neighborhood: algo::Neighborhood,
+    cell_transform: &Transform2D,
+    world_space: bool,
+) -> Vector2 {
+    if !world_space {
+        return Vector2 { x: vx, y: vy };
+    }
+    let (cx, cy) = neighborhood.cell_vec((vx, vy));
+    let v = cell_transform.basis_xform(Vector2 { x: cx, y: cy });
+    if v.length() > f32::EPSILON {
+        v.normalized()
+    } else {
+        Vector2::ZERO
     }
 }
 
@@ -17,7 +43,8 @@ trait HasDim {
     fn mut_dim(&mut self) -> &mut algo::Dimensions;
 
     fn recalculate_dim(&mut self) {
-        *self.mut_dim() = algo::Dimensions::new(self.width(), self.height())
+        let origin = self.mut_dim().origin();
+        *self.mut_dim() = algo::Dimensions::new(self.width(), self.height()).with_origin(origin)
     }
 }
 
@@ -29,6 +56,20 @@ impl FlowFieldFactory {
             width: dim.width() as u64,
             height: dim.height() as u64,
             opt_field,
+            neighborhood: algo::Neighborhood::default(),
+            integration_method: IntegrationMethod::default(),
+            targets: vec![],
+            opt_integration: None,
+            cell_transform: Transform2D::IDENTITY,
+            world_space: false,
+            unit_size: 1,
+            profile: String::new(),
+            max_cost: None,
+            out_of_range: vec![],
+            safety_distance: None,
+            snap_radius: 0,
+            density_revision: 0,
+            overlay_revision: 0,
         }
     }
 }
@@ -44,6 +85,20 @@ pub struct FlowField {
     height: u64,
     #[property]
     opt_field: Option<algo::FlowField>,
+    neighborhood: algo::Neighborhood,
+    integration_method: IntegrationMethod,
+    targets: Vec<algo::Target>,
+    opt_integration: Option<algo::IntegrationField>,
+    cell_transform: Transform2D,
+    world_space: bool,
+    unit_size: u64,
+    profile: String,
+    max_cost: Option<f32>,
+    out_of_range: algo::RangeField,
+    safety_distance: Option<f32>,
+    snap_radius: u64,
+    density_revision: u64,
+    overlay_revision: u64,
 }
 
 impl HasDim for FlowField {
@@ -59,29 +114,234 @@ impl HasDim for FlowField {
 }
 
 impl FlowField {
-    fn can_flow_internal(&self, (from_x, from_y): algo::Coord) -> bool {
-        self.dim.in_bounds(from_x, from_y)
-            && self.opt_field.as_ref().map_or(false, |field| {
-                field[self.dim.project_to_field_idx(from_x, from_y)].is_some()
-            })
+    /// Records the neighborhood the flow field was calculated with.
+    pub fn with_neighborhood(mut self, neighborhood: algo::Neighborhood) -> Self {
+        self.neighborhood = neighborhood;
+        self
     }
 
-    fn flow_internal(
-        &self,
-        from @ (from_x, from_y): algo::Coord,
-    ) -> Result<algo::Vector2D, String> {
-        if !self.dim.in_bounds(from_x, from_y) {
+    /// Records the integration method the integration field was calculated with.
+    pub fn with_integration_method(mut self, integration_method: IntegrationMethod) -> Self {
+        self.integration_method = integration_method;
+        self
+    }
+
+    /// Records the TileMap's cell transform, which maps cell units into the TileMap's local space.
+    pub fn with_cell_transform(mut self, cell_transform: Transform2D) -> Self {
+        self.cell_transform = cell_transform;
+        self
+    }
+
+    /// Records the footprint of the units the flow field was calculated for.
+    pub fn with_unit_size(mut self, unit_size: u64) -> Self {
+        self.unit_size = unit_size;
+        self
+    }
+
+    /// Records the movement profile the flow field was calculated for.
+    pub fn with_profile(mut self, profile: String) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Records the cost beyond which the integration stopped,
+    /// and the tiles that were left out of range because of it.
+    pub fn with_max_cost(mut self, max_cost: Option<f32>, out_of_range: algo::RangeField) -> Self {
+        self.max_cost = max_cost;
+        self.out_of_range = out_of_range;
+        self
+    }
+
+    /// Marks the flow field as fleeing from its targets, up to the safety distance.
+    pub fn with_safety_distance(mut self, safety_distance: Option<f32>) -> Self {
+        self.safety_distance = safety_distance;
+        self
+    }
+
+    /// Lets agents on tiles that can't flow head for the nearest tile within the radius that can.
+    pub fn with_snap_radius(mut self, snap_radius: u64) -> Self {
+        self.snap_radius = snap_radius;
+        self
+    }
+
+    /// Records the generator's crowd density the flow field was calculated with.
+    pub fn with_density_revision(mut self, density_revision: u64) -> Self {
+        self.density_revision = density_revision;
+        self
+    }
+
+    /// Records the generator's overlays the flow field was calculated with.
+    pub fn with_overlay_revision(mut self, overlay_revision: u64) -> Self {
+        self.overlay_revision = overlay_revision;
+        self
+    }
+
+    pub fn with_world_space(mut self, world_space: bool) -> Self {
+        self.world_space = world_space;
+        self
+    }
+
+    /// Keeps the targets and the integration field, if given, for repairs and travel costs.
+    pub fn with_integration(
+        mut self,
+        targets: Vec<algo::Target>,
+        opt_integration: Option<algo::IntegrationField>,
+    ) -> Self {
+        self.targets = targets;
+        self.opt_integration = opt_integration;
+        self
+    }
+
+    pub fn dim(&self) -> &algo::Dimensions {
+        &self.dim
+    }
+
+    pub fn neighborhood(&self) -> algo::Neighborhood {
+        self.neighborhood
+    }
+
+    pub fn integration_method(&self) -> IntegrationMethod {
+        self.integration_method
+    }
+
+    pub fn targets(&self) -> &[algo::Target] {
+        &self.targets
+    }
+
+    pub fn integration(&self) -> Option<&algo::IntegrationField> {
+        self.opt_integration.as_ref()
+    }
+
+    pub fn unit_size(&self) -> u64 {
+        self.unit_size
+    }
+
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    pub fn world_space(&self) -> bool {
+        self.world_space
+    }
+
+    pub fn max_cost(&self) -> Option<f32> {
+        self.max_cost
+    }
+
+    pub fn safety_distance(&self) -> Option<f32> {
+        self.safety_distance
+    }
+
+    pub fn density_revision(&self) -> u64 {
+        self.density_revision
+    }
+
+    pub fn overlay_revision(&self) -> u64 {
+        self.overlay_revision
+    }
+
+    fn output_vec(&self, v: algo::Vector2D, world_space: bool) -> Vector2 {
+        output_vec(v, self.neighborhood, &self.cell_transform, world_space)
+    }
+
+    fn can_flow_internal(&self, from: algo::Coord) -> bool {
+        self.flow_internal(from).is_ok()
+    }
+
+    /// Whether the tile, in field coordinates, lies beyond the cost the integration stopped at.
+    fn beyond_max_cost(&self, (x, y): algo::Coord) -> bool {
+        self.dim.in_bounds(x, y)
+            && matches!(
+                self.out_of_range.get(self.dim.project_to_field_idx(x, y)),
+                Some(true)
+            )
+    }
+
+    /// Queries take TileMap cells, which are converted into the field by its origin.
+    fn flow_internal(&self, from: algo::Coord) -> Result<algo::Vector2D, String> {
+        let local @ (x, y) = self.dim.cell_to_local(from);
+        if !self.dim.in_bounds(x, y) {
             return Err(format!("FlowField: position {:#?} out of bounds!", from));
         } else if let Some(field) = &self.opt_field {
-            if let Some((vx, vy)) = field[self.dim.project_to_field_idx(from_x, from_y)] {
+            let snapped = algo::snap_flow(
+                &self.dim,
+                self.neighborhood,
+                field,
+                local,
+                self.snap_radius as usize,
+            );
+            if let Some((vx, vy)) = snapped {
                 return Ok((vx, vy));
             }
         }
+        if self.beyond_max_cost(local) {
+            return Err(format!("FlowField: position {:#?} out of range!", from));
+        }
         Err(format!(
             "FlowField: unreachable position {:#?} queried!",
             from
         ))
     }
+
+    /// Tiles along the flow from the tile, at most `max_steps` steps or unlimited if zero.
+    fn trace_path_internal(
+        &self,
+        from: algo::Coord,
+        max_steps: u64,
+    ) -> Result<Vector2Array, String> {
+        let local @ (x, y) = self.dim.cell_to_local(from);
+        if !self.dim.in_bounds(x, y) {
+            return Err(format!("FlowField: position {:#?} out of bounds!", from));
+        }
+        let field = self
+            .opt_field
+            .as_ref()
+            .ok_or_else(|| format!("FlowField: unreachable position {:#?} queried!", from))?;
+        let max_steps = if max_steps == 0 {
+            usize::MAX
+        } else {
+            max_steps as usize
+        };
+        Ok(
+            algo::trace_flow_field(&self.dim, self.neighborhood, field, local, max_steps)
+                .into_iter()
+                .map(|tile| {
+                    let (x, y) = self.dim.local_to_cell(tile);
+                    Vector2::new(x as f32, y as f32)
+                })
+                .collect(),
+        )
+    }
+
+    fn distance_internal(&self, from: algo::Coord) -> Result<f32, String> {
+        let (x, y) = self.dim.cell_to_local(from);
+        if !self.dim.in_bounds(x, y) {
+            return Err(format!("FlowField: position {:#?} out of bounds!", from));
+        }
+        match &self.opt_integration {
+            None => Err("FlowField: no integration field kept!".to_owned()),
+            Some(integration) => {
+                Ok(integration[self.dim.project_to_field_idx(x, y)].unwrap_or(f32::INFINITY))
+            }
+        }
+    }
+
+    fn sample_internal(&self, pos @ (px, py): algo::Vector2D) -> Result<algo::Vector2D, String> {
+        let cell = (px.floor() as isize, py.floor() as isize);
+        let (x, y) = self.dim.cell_to_local(cell);
+        if !self.dim.in_bounds(x, y) {
+            return Err(format!("FlowField: position {:#?} out of bounds!", pos));
+        } else if self.neighborhood.is_hex() {
+            /* blending assumes square tiles, hex tiles keep the vector of the tile at the position. */
+            return self.flow_internal(cell);
+        } else if let Some(field) = &self.opt_field {
+            if let Some(v) = algo::sample_flow_field(&self.dim, field, self.dim.pos_to_local(pos)) {
+                return Ok(v);
+            }
+        }
+        /* the tile at the position can't flow, agents may still snap back from it. */
+        self.flow_internal(cell)
+    }
 }
 
 #[methods]
@@ -92,6 +352,20 @@ impl FlowField {
             width: 0,
             height: 0,
             opt_field: None,
+            neighborhood: algo::Neighborhood::default(),
+            integration_method: IntegrationMethod::default(),
+            targets: vec![],
+            opt_integration: None,
+            cell_transform: Transform2D::IDENTITY,
+            world_space: false,
+            unit_size: 1,
+            profile: String::new(),
+            max_cost: None,
+            out_of_range: vec![],
+            safety_distance: None,
+            snap_radius: 0,
+            density_revision: 0,
+            overlay_revision: 0,
         }
     }
 
@@ -102,6 +376,107 @@ impl FlowField {
             .with_setter(|s: &mut Self, _, new_val: Option<algo::FlowField>| s.opt_field = new_val)
             .with_default(None)
             .done();
+        builder
+            .property("neighborhood")
+            .with_getter(|s: &Self, _| s.neighborhood as i64)
+            .with_setter(|s: &mut Self, _, new_val: i64| {
+                s.neighborhood = algo::Neighborhood::from_i64(new_val)
+            })
+            .with_default(algo::Neighborhood::Eight as i64)
+            .done();
+        builder
+            .property("integration_method")
+            .with_getter(|s: &Self, _| s.integration_method as i64)
+            .with_setter(|s: &mut Self, _, new_val: i64| {
+                s.integration_method = IntegrationMethod::from_i64(new_val)
+            })
+            .with_default(IntegrationMethod::Dijkstra as i64)
+            .done();
+        builder
+            .property("targets")
+            .with_getter(|s, _| s.targets.to_owned())
+            .with_setter(|s: &mut Self, _, new_val: Vec<algo::Target>| s.targets = new_val)
+            .with_default(vec![])
+            .done();
+        builder
+            .property("integration")
+            .with_getter(|s, _| s.opt_integration.to_owned())
+            .with_setter(|s: &mut Self, _, new_val: Option<algo::IntegrationField>| {
+                s.opt_integration = new_val
+            })
+            .with_default(None)
+            .done();
+        builder
+            .property("unit_size")
+            .with_getter(|s: &Self, _| s.unit_size)
+            .with_setter(|s: &mut Self, _, new_val: u64| s.unit_size = new_val.max(1))
+            .with_default(1)
+            .done();
+        builder
+            .property("profile")
+            .with_getter(|s: &Self, _| s.profile.clone())
+            .with_setter(|s: &mut Self, _, new_val: String| s.profile = new_val)
+            .with_default(String::new())
+            .done();
+        builder
+            .property("max_cost")
+            .with_getter(|s: &Self, _| s.max_cost)
+            .with_setter(|s: &mut Self, _, new_val: Option<f32>| s.max_cost = new_val)
+            .with_default(None)
+            .done();
+        builder
+            .property("out_of_range")
+            .with_getter(|s: &Self, _| s.out_of_range.to_owned())
+            .with_setter(|s: &mut Self, _, new_val: algo::RangeField| s.out_of_range = new_val)
+            .with_default(vec![])
+            .done();
+        builder
+            .property("safety_distance")
+            .with_getter(|s: &Self, _| s.safety_distance)
+            .with_setter(|s: &mut Self, _, new_val: Option<f32>| s.safety_distance = new_val)
+            .with_default(None)
+            .done();
+        builder
+            .property("origin")
+            .with_getter(|s: &Self, _| {
+                let (x, y) = s.dim.origin();
+                Vector2::new(x as f32, y as f32)
+            })
+            .with_setter(|s: &mut Self, _, new_val: Vector2| {
+                s.dim = s.dim.with_origin((new_val.x as isize, new_val.y as isize))
+            })
+            .with_default(Vector2::ZERO)
+            .done();
+        builder
+            .property("snap_radius")
+            .with_getter(|s: &Self, _| s.snap_radius)
+            .with_setter(|s: &mut Self, _, new_val| s.snap_radius = new_val)
+            .with_default(0)
+            .done();
+        builder
+            .property("density_revision")
+            .with_getter(|s: &Self, _| s.density_revision)
+            .with_setter(|s: &mut Self, _, new_val| s.density_revision = new_val)
+            .with_default(0)
+            .done();
+        builder
+            .property("overlay_revision")
+            .with_getter(|s: &Self, _| s.overlay_revision)
+            .with_setter(|s: &mut Self, _, new_val| s.overlay_revision = new_val)
+            .with_default(0)
+            .done();
+        builder
+            .property("cell_transform")
+            .with_getter(|s: &Self, _| s.cell_transform)
+            .with_setter(|s: &mut Self, _, new_val| s.cell_transform = new_val)
+            .with_default(Transform2D::IDENTITY)
+            .done();
+        builder
+            .property("world_space")
+            .with_getter(|s: &Self, _| s.world_space)
+            .with_setter(|s: &mut Self, _, new_val| s.world_space = new_val)
+            .with_default(false)
+            .done();
         builder
             .property("width")
             .with_getter(|s: &Self, _| s.width)
@@ -133,6 +508,24 @@ impl FlowField {
         }
     }
 
+    /**
+     * Whether the tile has no flow because the flow field was calculated with a `max_cost`
+     * and stopped before reaching it, even though the tile could reach a target.
+     * Unreachable and impassable tiles are never out of range.
+     */
+    #[method]
+    fn is_out_of_range(&self, #[base] _owner: TRef<'_, Resource>, from: Vector2) -> bool {
+        match round_vec(from) {
+            Err(msg) => {
+                godot_error!("FlowField: {}", msg);
+                false
+            }
+            Ok(from) => {
+                self.beyond_max_cost(self.dim.cell_to_local(from)) && !self.can_flow_internal(from)
+            }
+        }
+    }
+
     #[method]
     fn flow(&self, #[base] _owner: TRef<'_, Resource>, from: Vector2) -> Vector2 {
         match round_vec(from) {
@@ -141,7 +534,84 @@ impl FlowField {
             }
             Ok((x, y)) => match self.flow_internal((x, y)) {
                 Err(m) => godot_warn!("FlowField: {}", m),
-                Ok((vx, vy)) => return Vector2 { x: vx, y: vy },
+                Ok(v) => return self.output_vec(v, self.world_space),
+            },
+        }
+        Vector2::ZERO
+    }
+
+    /**
+     * Tiles an agent on the tile passes on its way, following the flow field tile by tile,
+     * e.g. for drawing move-order previews. Includes the tile itself, and ends at the target,
+     * before a tile that can't flow or before running in circles.
+     * Stops after `max_steps` steps, unless zero.
+     */
+    #[method]
+    fn trace_path(
+        &self,
+        #[base] _owner: TRef<'_, Resource>,
+        from: Vector2,
+        #[opt] max_steps: u64,
+    ) -> Vector2Array {
+        match round_vec(from) {
+            Err(msg) => {
+                godot_error!("FlowField: {}", msg);
+            }
+            Ok((x, y)) => match self.trace_path_internal((x, y), max_steps) {
+                Err(m) => godot_warn!("{}", m),
+                Ok(path) => return path,
+            },
+        }
+        Vector2Array::new()
+    }
+
+    /**
+     * Travel cost from the tile to the nearest target, including the targets' starting costs.
+     * `INF` if the tile is unreachable or out of range.
+     */
+    #[method]
+    fn distance(&self, #[base] _owner: TRef<'_, Resource>, from: Vector2) -> f32 {
+        match round_vec(from) {
+            Err(msg) => {
+                godot_error!("FlowField: {}", msg);
+            }
+            Ok((x, y)) => match self.distance_internal((x, y)) {
+                Err(m) => godot_warn!("{}", m),
+                Ok(distance) => return distance,
+            },
+        }
+        f32::INFINITY
+    }
+
+    /**
+     * Travel costs of all tiles, row by row, `INF` for tiles that are unreachable or out of range.
+     * Empty unless the generator kept the integration field, see its `keep_integration_field`.
+     */
+    #[method]
+    fn get_integration_field(&self, #[base] _owner: TRef<'_, Resource>) -> Float32Array {
+        self.opt_integration
+            .as_ref()
+            .map_or_else(Float32Array::new, |integration| {
+                integration
+                    .iter()
+                    .map(|c| c.unwrap_or(f32::INFINITY))
+                    .collect()
+            })
+    }
+
+    /**
+     * Like `flow`, but blends the vectors of the four tiles around a continuous map position.
+     * Avoids sudden direction changes when agents cross tile boundaries.
+     */
+    #[method]
+    fn sample(&self, #[base] _owner: TRef<'_, Resource>, pos: Vector2) -> Vector2 {
+        match finite_vec(pos) {
+            Err(msg) => {
+                godot_error!("FlowField: {}", msg);
+            }
+            Ok(pos) => match self.sample_internal(pos) {
+                Err(m) => godot_warn!("FlowField: {}", m),
+                Ok(v) => return self.output_vec(v, self.world_space),
             },
         }
         Vector2::ZERO
@@ -156,6 +626,7 @@ impl BakedFlowFieldsFactory {
             width: dim.width() as u64,
             height: dim.height() as u64,
             flow_fields: fields,
+            world_space: false,
         }
     }
 }
@@ -171,6 +642,7 @@ pub struct BakedFlowFields {
     height: u64,
     #[property]
     flow_fields: Vec<FlowField>,
+    world_space: bool,
 }
 impl HasDim for BakedFlowFields {
     fn width(&self) -> usize {
@@ -191,6 +663,7 @@ impl BakedFlowFields {
             width: 0,
             height: 0,
             flow_fields: vec![],
+            world_space: false,
         }
     }
 
@@ -201,6 +674,23 @@ impl BakedFlowFields {
             .with_setter(|s: &mut Self, _, new_val: Vec<FlowField>| s.flow_fields = new_val)
             .with_default(vec![])
             .done();
+        builder
+            .property("world_space")
+            .with_getter(|s: &Self, _| s.world_space)
+            .with_setter(|s: &mut Self, _, new_val| s.world_space = new_val)
+            .with_default(false)
+            .done();
+        builder
+            .property("origin")
+            .with_getter(|s: &Self, _| {
+                let (x, y) = s.dim.origin();
+                Vector2::new(x as f32, y as f32)
+            })
+            .with_setter(|s: &mut Self, _, new_val: Vector2| {
+                s.dim = s.dim.with_origin((new_val.x as isize, new_val.y as isize))
+            })
+            .with_default(Vector2::ZERO)
+            .done();
         builder
             .property("width")
             .with_getter(|s: &Self, _| s.width)
@@ -221,6 +711,14 @@ impl BakedFlowFields {
             .done();
     }
 
+    /// The baked flow field towards the TileMap cell, if it lies on the map.
+    fn field_towards(&self, to: algo::Coord) -> Option<&FlowField> {
+        let (x, y) = self.dim.cell_to_local(to);
+        self.dim
+            .in_bounds(x, y)
+            .then(|| &self.flow_fields[self.dim.project_to_field_idx(x, y)])
+    }
+
     #[method]
     fn can_flow_from_to(
         &self,
@@ -233,11 +731,9 @@ impl BakedFlowFields {
                 godot_error!("BakedFlowFields: {}", msg);
                 false
             }
-            Ok(((to_x, to_y), (from_x, from_y))) => {
-                self.dim.in_bounds(to_x, to_y)
-                    && self.flow_fields[self.dim.project_to_field_idx(to_x, to_y)]
-                        .can_flow_internal((from_x, from_y))
-            }
+            Ok((to, from)) => self
+                .field_towards(to)
+                .map_or(false, |field| field.can_flow_internal(from)),
         }
     }
 
@@ -253,14 +749,199 @@ impl BakedFlowFields {
                 godot_error!("FlowField: {}", msg);
                 Vector2::ZERO
             }
-            Ok(((to_x, to_y), (from_x, from_y))) => self.flow_fields
-                [self.dim.project_to_field_idx(to_x, to_y)]
-            .flow_internal((from_x, from_y))
-            .map(|(vx, vy)| Vector2 { x: vx, y: vy })
-            .unwrap_or_else(|e| {
-                godot_warn!("BakedFlowField: Error querying baked flow field {}", e);
+            Ok((cell, _)) if self.field_towards(cell).is_none() => {
+                godot_warn!("BakedFlowFields: target {} out of bounds!", to.to_variant());
                 Vector2::ZERO
-            }),
+            }
+            Ok((cell, from)) => {
+                let field = self.field_towards(cell).unwrap();
+                field
+                    .flow_internal(from)
+                    .map(|v| field.output_vec(v, self.world_space))
+                    .unwrap_or_else(|e| {
+                        godot_warn!("BakedFlowField: Error querying baked flow field {}", e);
+                        Vector2::ZERO
+                    })
+            }
+        }
+    }
+
+    #[method]
+    fn trace_path_from_to(
+        &self,
+        #[base] _owner: TRef<'_, Resource>,
+        from: Vector2,
+        to: Vector2,
+        #[opt] max_steps: u64,
+    ) -> Vector2Array {
+        match round_vec(to).and_then(|round1| Ok((round1, round_vec(from)?))) {
+            Err(msg) => godot_error!("BakedFlowFields: {}", msg),
+            Ok((cell, _)) if self.field_towards(cell).is_none() => {
+                godot_warn!("BakedFlowFields: target {} out of bounds!", to.to_variant())
+            }
+            Ok((cell, from)) => {
+                let field = self.field_towards(cell).unwrap();
+                match field.trace_path_internal(from, max_steps) {
+                    Err(e) => godot_warn!("BakedFlowField: Error querying baked flow field {}", e),
+                    Ok(path) => return path,
+                }
+            }
         }
+        Vector2Array::new()
+    }
+
+    /**
+     * Like `distance` on the baked flow field towards `to`.
+     */
+    #[method]
+    fn distance_from_to(
+        &self,
+        #[base] _owner: TRef<'_, Resource>,
+        from: Vector2,
+        to: Vector2,
+    ) -> f32 {
+        match round_vec(to).and_then(|round1| Ok((round1, round_vec(from)?))) {
+            Err(msg) => godot_error!("BakedFlowFields: {}", msg),
+            Ok((cell, _)) if self.field_towards(cell).is_none() => {
+                godot_warn!("BakedFlowFields: target {} out of bounds!", to.to_variant())
+            }
+            Ok((cell, from)) => {
+                let field = self.field_towards(cell).unwrap();
+                match field.distance_internal(from) {
+                    Err(e) => godot_warn!("BakedFlowField: Error querying baked flow field {}", e),
+                    Ok(distance) => return distance,
+                }
+            }
+        }
+        f32::INFINITY
+    }
+
+    #[method]
+    fn sample_from_to(
+        &self,
+        #[base] _owner: TRef<'_, Resource>,
+        from: Vector2,
+        to: Vector2,
+    ) -> Vector2 {
+        match round_vec(to).and_then(|round1| Ok((round1, finite_vec(from)?))) {
+            Err(msg) => godot_error!("BakedFlowFields: {}", msg),
+            Ok((cell, _)) if self.field_towards(cell).is_none() => {
+                godot_warn!("BakedFlowFields: target {} out of bounds!", to.to_variant())
+            }
+            Ok((cell, from)) => {
+                let field = self.field_towards(cell).unwrap();
+                match field.sample_internal(from) {
+                    Err(e) => godot_warn!("BakedFlowField: Error querying baked flow field {}", e),
+                    Ok(v) => return field.output_vec(v, self.world_space),
+                }
+            }
+        }
+        Vector2::ZERO
+    }
+}
+
+pub struct SectorFlowFieldFactory {}
+impl SectorFlowFieldFactory {
+    pub fn create(flow: SectorFlow, cell_transform: Transform2D) -> SectorFlowField {
+        SectorFlowField {
+            opt_flow: Some(flow),
+            cell_transform,
+            world_space: false,
+            snap_radius: 0,
+        }
+    }
+}
+
+/// Flow field over the sectors of a large map.
+/// The local flow field of a sector is only calculated once an agent queries a tile within it.
+/// Cannot be saved, since it is tied to the generator's current map.
+#[derive(NativeClass)]
+#[inherit(Resource)]
+pub struct SectorFlowField {
+    opt_flow: Option<SectorFlow>,
+    cell_transform: Transform2D,
+    #[property]
+    world_space: bool,
+    #[property]
+    snap_radius: u64,
+}
+
+impl SectorFlowField {
+    /// Lets agents on tiles that can't flow head for the nearest passable tile within the radius.
+    pub fn with_snap_radius(mut self, snap_radius: u64) -> Self {
+        self.snap_radius = snap_radius;
+        self
+    }
+
+    fn flow_internal(&mut self, from: algo::Coord) -> Result<algo::Vector2D, String> {
+        let Some(flow) = &mut self.opt_flow else {
+            return Err("SectorFlowField: not calculated by a FlowFieldGenerator!".to_owned());
+        };
+        match flow.dim().cell_to_local(from) {
+            (x, y) if !flow.dim().in_bounds(x, y) => Err(format!(
+                "SectorFlowField: position {:#?} out of bounds!",
+                from
+            )),
+            local => flow
+                .snap_flow(local, self.snap_radius as usize)
+                .ok_or_else(|| {
+                    format!("SectorFlowField: unreachable position {:#?} queried!", from)
+                }),
+        }
+    }
+}
+
+#[methods]
+impl SectorFlowField {
+    fn new(_owner: &Resource) -> Self {
+        Self {
+            opt_flow: None,
+            cell_transform: Transform2D::IDENTITY,
+            world_space: false,
+            snap_radius: 0,
+        }
+    }
+
+    #[method]
+    fn can_flow(&mut self, #[base] _owner: TRef<'_, Resource>, from: Vector2) -> bool {
+        match round_vec(from) {
+            Err(msg) => {
+                godot_error!("SectorFlowField: {}", msg);
+                false
+            }
+            Ok((x, y)) => self.flow_internal((x, y)).is_ok(),
+        }
+    }
+
+    #[method]
+    fn flow(&mut self, #[base] _owner: TRef<'_, Resource>, from: Vector2) -> Vector2 {
+        match round_vec(from) {
+            Err(msg) => {
+                godot_error!("SectorFlowField: {}", msg);
+            }
+            Ok((x, y)) => match self.flow_internal((x, y)) {
+                Err(m) => godot_warn!("{}", m),
+                Ok(v) => {
+                    let neighborhood = self.opt_flow.as_ref().map(|flow| flow.neighborhood());
+                    return output_vec(
+                        v,
+                        neighborhood.unwrap_or_default(),
+                        &self.cell_transform,
+                        self.world_space,
+                    );
+                }
+            },
+        }
+        Vector2::ZERO
+    }
+
+    /**
+     * Number of sectors whose flow field has been calculated so far.
+     */
+    #[method]
+    fn calculated_sectors(&self, #[base] _owner: TRef<'_, Resource>) -> u64 {
+        self.opt_flow
+            .as_ref()
+            .map_or(0, |flow| flow.calculated_sectors() as u64)
     }
 }
@@ -1,22 +1,262 @@
 use gdnative::api::{Node, TileMap};
+use gdnative::export::hint::{EnumHint, IntHint};
 use gdnative::prelude::*;
 use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
 use crate::algo;
-use crate::flowfield::BakedFlowFieldsFactory;
+use crate::flowfield::{round_vec, BakedFlowFieldsFactory, SectorFlowFieldFactory};
+use crate::sectors::{SectorFlow, SectorGraph};
 use crate::tilecost::{self};
 
+/// How flow vectors are derived from the integration field.
+#[derive(Clone, Copy, PartialEq, Eq, Default, ToVariant, FromVariant)]
+pub enum FlowMode {
+    /// Towards the cheapest of the eight neighbors.
+    #[default]
+    Octile,
+    /// Along the gradient of the integration field.
+    Gradient,
+}
+
+impl FlowMode {
+    const NAMES: [&'static str; 2] = ["Octile", "Gradient"];
+
+    fn from_i64(v: i64) -> Self {
+        match v {
+            1 => FlowMode::Gradient,
+            _ => FlowMode::Octile,
+        }
+    }
+}
+
+/// How the integration field is propagated from the targets.
+#[derive(Clone, Copy, PartialEq, Eq, Default, ToVariant, FromVariant)]
+pub enum IntegrationMethod {
+    /// Graph-based, along the neighborhood of each tile.
+    #[default]
+    Dijkstra,
+    /// Eikonal solver giving near-Euclidean arrival times.
+    FastMarching,
+}
+
+impl IntegrationMethod {
+    const NAMES: [&'static str; 2] = ["Dijkstra", "FastMarching"];
+
+    pub(crate) fn from_i64(v: i64) -> Self {
+        match v {
+            1 => IntegrationMethod::FastMarching,
+            _ => IntegrationMethod::Dijkstra,
+        }
+    }
+}
+
+/// Copy of the generator's properties, so flow fields can be calculated off the main thread.
+#[derive(Clone, Copy)]
+struct FlowSettings {
+    neighborhood: algo::Neighborhood,
+    integration_method: IntegrationMethod,
+    flow_mode: FlowMode,
+    line_of_sight: bool,
+    cell_transform: Transform2D,
+    snap_radius: usize,
+    keep_integration_field: bool,
+}
+
+impl FlowSettings {
+    /// Moves targets on impassable tiles to the nearest passable tile within the snap radius.
+    /// Targets without one stay where they are, and are ignored by the integration.
+    fn snap_targets(
+        &self,
+        dim: &algo::Dimensions,
+        cost: &algo::CostField,
+        targets: &[algo::Target],
+    ) -> Vec<algo::Target> {
+        targets
+            .iter()
+            .map(|&(to, initial_cost)| {
+                let snapped =
+                    algo::snap_to_nearest(dim, self.neighborhood, to, self.snap_radius, |idx| {
+                        cost[idx].is_some()
+                    });
+                (snapped.unwrap_or(to), initial_cost)
+            })
+            .collect()
+    }
+
+    fn integrate(
+        &self,
+        dim: &algo::Dimensions,
+        cost: &algo::CostField,
+        entry_costs: Option<&algo::EntryCostField>,
+        targets: &[algo::Target],
+    ) -> Option<algo::IntegrationField> {
+        match self.method(entry_costs) {
+            IntegrationMethod::FastMarching => {
+                algo::calculate_eikonal_integration_field(dim, targets, cost)
+            }
+            IntegrationMethod::Dijkstra => algo::calculate_integration_field(
+                dim,
+                self.neighborhood,
+                targets,
+                cost,
+                entry_costs,
+            ),
+        }
+    }
+
+    /// Directional entry costs are only respected by Dijkstra integration,
+    /// so maps with directional tiles always use it.
+    fn method(&self, entry_costs: Option<&algo::EntryCostField>) -> IntegrationMethod {
+        match entry_costs {
+            None => self.integration_method,
+            Some(_) => IntegrationMethod::Dijkstra,
+        }
+    }
+
+    /// Like `integrate`, but leaves tiles costing more than `max_cost` without a value.
+    /// Also marks the tiles that were left out that way.
+    fn integrate_within(
+        &self,
+        dim: &algo::Dimensions,
+        cost: &algo::CostField,
+        entry_costs: Option<&algo::EntryCostField>,
+        targets: &[algo::Target],
+        max_cost: f32,
+    ) -> Option<(algo::IntegrationField, algo::RangeField)> {
+        match self.method(entry_costs) {
+            IntegrationMethod::FastMarching => {
+                algo::calculate_eikonal_integration_field_within(dim, targets, cost, max_cost)
+            }
+            IntegrationMethod::Dijkstra => algo::calculate_integration_field_within(
+                dim,
+                self.neighborhood,
+                targets,
+                cost,
+                entry_costs,
+                max_cost,
+            ),
+        }
+    }
+
+    /// Like `integrate`, but reuses a previous integration field where the method allows for it.
+    fn reintegrate(
+        &self,
+        dim: &algo::Dimensions,
+        cost: &algo::CostField,
+        entry_costs: Option<&algo::EntryCostField>,
+        targets: &[algo::Target],
+        previous: &algo::IntegrationField,
+        changed: &[algo::Coord],
+    ) -> Option<algo::IntegrationField> {
+        match self.method(entry_costs) {
+            IntegrationMethod::FastMarching => self.integrate(dim, cost, entry_costs, targets),
+            IntegrationMethod::Dijkstra => {
+                let mut integration_field = previous.clone();
+                algo::repair_integration_field(
+                    dim,
+                    self.neighborhood,
+                    targets,
+                    cost,
+                    entry_costs,
+                    &mut integration_field,
+                    changed,
+                );
+                integration_field
+                    .iter()
+                    .any(Option::is_some)
+                    .then_some(integration_field)
+            }
+        }
+    }
+
+    /// Integration field of a flee field, leading away from the threats up to the safety distance.
+    fn integrate_flee(
+        &self,
+        dim: &algo::Dimensions,
+        cost: &algo::CostField,
+        entry_costs: Option<&algo::EntryCostField>,
+        threats: &[algo::Target],
+        safety_distance: f32,
+    ) -> Option<algo::IntegrationField> {
+        let towards_threats = self.integrate(dim, cost, entry_costs, threats)?;
+        let targets = algo::flee_targets(dim, cost, &towards_threats, safety_distance);
+        self.integrate(dim, cost, entry_costs, &targets)
+    }
+
+    /// Directional entry costs are only respected by octile flow,
+    /// so maps with directional tiles always use it, without line of sight.
+    fn flow(
+        &self,
+        dim: &algo::Dimensions,
+        cost: &algo::CostField,
+        entry_costs: Option<&algo::EntryCostField>,
+        targets: &[algo::Target],
+        integration_field: &algo::IntegrationField,
+    ) -> algo::FlowField {
+        let mut field = match (self.flow_mode, entry_costs) {
+            (FlowMode::Gradient, None) => {
+                algo::calculate_gradient_flow_field(dim, self.neighborhood, integration_field)
+            }
+            _ => algo::calculate_flow_field(dim, self.neighborhood, integration_field, entry_costs),
+        };
+        if self.line_of_sight && entry_costs.is_none() {
+            algo::apply_line_of_sight(dim, cost, targets, integration_field, &mut field);
+        }
+        field
+    }
+
+    /// The integration field to keep on a flow field, only if the generator keeps them.
+    fn kept(
+        &self,
+        opt_integration_field: Option<algo::IntegrationField>,
+    ) -> Option<algo::IntegrationField> {
+        opt_integration_field.filter(|_| self.keep_integration_field)
+    }
+}
+
 #[derive(NativeClass, ToVariant, FromVariant, Default)]
 #[register_with(Self::register_properties)]
 #[inherit(Node)]
 pub struct FlowFieldGenerator {
     pub tile_map_path: NodePath,
+    pub neighborhood: algo::Neighborhood,
+    pub integration_method: IntegrationMethod,
+    pub flow_mode: FlowMode,
+    pub line_of_sight: bool,
+    pub sector_size: u64,
+    pub density_weight: f32,
+    pub snap_radius: u64,
+    pub keep_integration_field: bool,
+    /// Portal graph of the last sector flow field, reused as long as the map doesn't change.
+    #[variant(skip)]
+    sector_graph: Option<Arc<SectorGraph>>,
+    /// Additive cost layers by name, one cost per tile, summed in the order of their names.
+    #[variant(skip)]
+    overlays: BTreeMap<String, Vec<f32>>,
+    /// Bumped whenever an overlay is set or removed, as repairs can't tell where costs changed.
+    #[variant(skip)]
+    overlay_revision: u64,
+    /// Crowd density of the last reported agent positions, empty if none were reported.
+    #[variant(skip)]
+    density: Vec<f32>,
+    /// Bumped whenever the crowd density or its weight changes, as repairs can't tell where.
+    #[variant(skip)]
+    density_revision: u64,
+    /// Regions of the last `compute_regions` by profile and unit size, answering `are_connected`.
+    #[variant(skip)]
+    regions: BTreeMap<(String, u64), (algo::Dimensions, algo::RegionField)>,
 }
 
 #[methods]
 impl FlowFieldGenerator {
     fn new(_base: &Node) -> Self {
-        FlowFieldGenerator::default()
+        FlowFieldGenerator {
+            sector_size: 16,
+            density_weight: 1.0,
+            ..Default::default()
+        }
     }
 
     fn register_properties(builder: &ClassBuilder<FlowFieldGenerator>) {
@@ -28,24 +268,192 @@ impl FlowFieldGenerator {
             })
             .with_default(NodePath::default())
             .done();
+        builder
+            .property::<i64>("neighborhood")
+            .with_getter(|n: &Self, _base: TRef<Node>| n.neighborhood as i64)
+            .with_setter(|n: &mut Self, _base: TRef<Node>, new_value: i64| {
+                n.neighborhood = match algo::Neighborhood::from_i64(new_value) {
+                    neighborhood if neighborhood.is_hex() => algo::Neighborhood::Eight,
+                    neighborhood => neighborhood,
+                }
+            })
+            .with_hint(IntHint::Enum(EnumHint::new(
+                algo::Neighborhood::SQUARE_NAMES
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect(),
+            )))
+            .with_default(algo::Neighborhood::Eight as i64)
+            .done();
+        builder
+            .property::<i64>("integration_method")
+            .with_getter(|n: &Self, _base: TRef<Node>| n.integration_method as i64)
+            .with_setter(|n: &mut Self, _base: TRef<Node>, new_value: i64| {
+                n.integration_method = IntegrationMethod::from_i64(new_value)
+            })
+            .with_hint(IntHint::Enum(EnumHint::new(
+                IntegrationMethod::NAMES
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect(),
+            )))
+            .with_default(IntegrationMethod::Dijkstra as i64)
+            .done();
+        builder
+            .property::<i64>("flow_mode")
+            .with_getter(|n: &Self, _base: TRef<Node>| n.flow_mode as i64)
+            .with_setter(|n: &mut Self, _base: TRef<Node>, new_value: i64| {
+                n.flow_mode = FlowMode::from_i64(new_value)
+            })
+            .with_hint(IntHint::Enum(EnumHint::new(
+                FlowMode::NAMES.iter().map(|n| n.to_string()).collect(),
+            )))
+            .with_default(FlowMode::Octile as i64)
+            .done();
+        builder
+            .property::<bool>("line_of_sight")
+            .with_getter(|n: &Self, _base: TRef<Node>| n.line_of_sight)
+            .with_setter(|n: &mut Self, _base: TRef<Node>, new_value: bool| {
+                n.line_of_sight = new_value
+            })
+            .with_default(false)
+            .done();
+        builder
+            .property::<u64>("sector_size")
+            .with_getter(|n: &Self, _base: TRef<Node>| n.sector_size)
+            .with_setter(|n: &mut Self, _base: TRef<Node>, new_value: u64| {
+                n.sector_size = new_value.max(1)
+            })
+            .with_default(16)
+            .done();
+        builder
+            .property::<f32>("density_weight")
+            .with_getter(|n: &Self, _base: TRef<Node>| n.density_weight)
+            .with_setter(|n: &mut Self, _base: TRef<Node>, new_value: f32| {
+                n.density_weight = new_value.max(0.);
+                n.density_revision += 1
+            })
+            .with_default(1.0)
+            .done();
+        builder
+            .property::<u64>("snap_radius")
+            .with_getter(|n: &Self, _base: TRef<Node>| n.snap_radius)
+            .with_setter(|n: &mut Self, _base: TRef<Node>, new_value: u64| {
+                n.snap_radius = new_value
+            })
+            .with_default(0)
+            .done();
+        builder
+            .property::<bool>("keep_integration_field")
+            .with_getter(|n: &Self, _base: TRef<Node>| n.keep_integration_field)
+            .with_setter(|n: &mut Self, _base: TRef<Node>, new_value: bool| {
+                n.keep_integration_field = new_value
+            })
+            .with_default(false)
+            .done();
+    }
+
+    /// The neighborhood of the tile map's cells, hexagonal if the cells are half offset.
+    /// Hex layouts are given for the field's local coordinates, which start at the map's origin.
+    fn get_neighborhood(
+        &self,
+        base: TRef<'_, Node>,
+        dim: &algo::Dimensions,
+    ) -> Result<algo::Neighborhood, GodotString> {
+        let tm = self.get_tile_map(base)?;
+        Ok(match tm.half_offset().0 {
+            TileMap::HALF_OFFSET_X => algo::Neighborhood::HexOddRows,
+            TileMap::HALF_OFFSET_NEGATIVE_X => algo::Neighborhood::HexEvenRows,
+            TileMap::HALF_OFFSET_Y => algo::Neighborhood::HexOddColumns,
+            TileMap::HALF_OFFSET_NEGATIVE_Y => algo::Neighborhood::HexEvenColumns,
+            _ => self.neighborhood,
+        }
+        .translated(dim.origin()))
+    }
+
+    /// Maps cell units into the tile map's local space, like the TileMap does for its cells.
+    fn get_cell_transform(&self, base: TRef<'_, Node>) -> Result<Transform2D, GodotString> {
+        let tm = self.get_tile_map(base)?;
+        let Vector2 { x: w, y: h } = tm.cell_size();
+        Ok(match tm.mode().0 {
+            TileMap::MODE_ISOMETRIC => Transform2D::from_basis_origin(
+                Vector2::new(w * 0.5, h * 0.5),
+                Vector2::new(-w * 0.5, h * 0.5),
+                Vector2::ZERO,
+            ),
+            TileMap::MODE_CUSTOM => tm.custom_transform(),
+            _ => Transform2D::from_basis_origin(
+                Vector2::new(w, 0.),
+                Vector2::new(0., h),
+                Vector2::ZERO,
+            ),
+        })
+    }
+
+    /// Hex maps always use Dijkstra integration and flow towards one of the six neighbors,
+    /// since the other methods assume square tiles.
+    fn settings(
+        &self,
+        base: TRef<'_, Node>,
+        dim: &algo::Dimensions,
+    ) -> Result<FlowSettings, GodotString> {
+        let neighborhood = self.get_neighborhood(base, dim)?;
+        let square = !neighborhood.is_hex();
+        Ok(FlowSettings {
+            neighborhood,
+            integration_method: if square {
+                self.integration_method
+            } else {
+                IntegrationMethod::Dijkstra
+            },
+            flow_mode: if square {
+                self.flow_mode
+            } else {
+                FlowMode::Octile
+            },
+            line_of_sight: square && self.line_of_sight,
+            cell_transform: self.get_cell_transform(base)?,
+            snap_radius: self.snap_radius as usize,
+            keep_integration_field: self.keep_integration_field,
+        })
     }
 
-    fn find_tile_cost(&self, base: TRef<'_, Node>, name: &str) -> Result<Option<f32>, GodotString> {
+    /// Cost of the tile and its entry costs, if it has directional ones.
+    /// A movement profile overrides them with the tile cost's child of the same name, if it has one.
+    fn find_tile_cost(
+        &self,
+        base: TRef<'_, Node>,
+        name: &str,
+        profile: &str,
+    ) -> Result<(algo::Cost, Option<algo::EntryCost>), GodotString> {
         let n = base.find_node(name, false, true).ok_or_else(|| {
             let err: GodotString =
                 format!("Child FlowFieldTileCost for tile {} not found!", name).into();
             err
         })?;
+        let n = match profile {
+            "" => n,
+            profile => {
+                let overridden = unsafe { n.assume_safe() }.find_node(profile, false, true);
+                overridden.unwrap_or(n)
+            }
+        };
         let c: TInstance<tilecost::FlowFieldTileCost> =
             unsafe { n.assume_safe() }.cast_instance().ok_or_else(|| {
                 let err: GodotString = format!("Child {} is not a FlowFieldTileCost.", name).into();
                 err
             })?;
-        c.map(|a, _| if !a.impassable { Some(a.cost) } else { None })
-            .map_err(|e| {
-                let err: GodotString = format!("Error borrowing tile cost user data: {}", e).into();
-                err
-            })
+        c.map(|a, _| {
+            if !a.impassable {
+                (Some(a.cost), a.entry_cost())
+            } else {
+                (None, None)
+            }
+        })
+        .map_err(|e| {
+            let err: GodotString = format!("Error borrowing tile cost user data: {}", e).into();
+            err
+        })
     }
 
     fn get_tile_map(&self, base: TRef<'_, Node>) -> Result<TRef<'_, TileMap>, GodotString> {
@@ -62,26 +470,63 @@ impl FlowFieldGenerator {
     #[method]
     fn _get_configuration_warning(&self, #[base] base: TRef<'_, Node>) -> GodotString {
         let mut err = self.get_tile_map(base).err().unwrap_or_default();
-        err += self.generate_cost_field(base).err().unwrap_or_default();
+        err += self.generate_cost_field(base, "").err().unwrap_or_default();
         err
     }
 
     fn get_map_dimensions(&self, base: TRef<'_, Node>) -> Result<algo::Dimensions, GodotString> {
         let tm = self.get_tile_map(base)?;
-        let Vector2 { x, y } = tm.get_used_rect().size;
+        let used_rect = tm.get_used_rect();
+        let Vector2 { x, y } = used_rect.size;
         if x < 1.0 {
             Err(format!("Map has illegal size. Got x: {}", x).into())
         } else if y < 1.0 {
             Err(format!("Map has illegal size. Got y: {}", y).into())
         } else {
-            Ok(algo::Dimensions::new(x as usize, y as usize))
+            let origin = (used_rect.position.x as isize, used_rect.position.y as isize);
+            Ok(algo::Dimensions::new(x as usize, y as usize).with_origin(origin))
         }
     }
 
+    /// Cost field of the map for the movement profile, with the overlays and crowd density added,
+    /// along with its entry costs if any tile has directional ones.
     fn generate_cost_field(
         &self,
         base: TRef<'_, Node>,
-    ) -> Result<(algo::Dimensions, algo::CostField), GodotString> {
+        profile: &str,
+    ) -> Result<
+        (
+            algo::Dimensions,
+            algo::CostField,
+            Option<algo::EntryCostField>,
+        ),
+        GodotString,
+    > {
+        let (dim, mut cost, entry_costs) = self.generate_uncrowded_cost_field(base, profile)?;
+        if !self.density.is_empty() {
+            if self.density.len() != dim.max_idx() {
+                return Err("Agent positions were reported for a map of a different size.".into());
+            }
+            for (c, d) in cost.iter_mut().zip(&self.density) {
+                *c = c.map(|c| c + self.density_weight * d);
+            }
+        }
+        Ok((dim, cost, entry_costs))
+    }
+
+    /// Like `generate_cost_field`, but without the crowd density.
+    fn generate_uncrowded_cost_field(
+        &self,
+        base: TRef<'_, Node>,
+        profile: &str,
+    ) -> Result<
+        (
+            algo::Dimensions,
+            algo::CostField,
+            Option<algo::EntryCostField>,
+        ),
+        GodotString,
+    > {
         let tm = self.get_tile_map(base)?;
         let ts_ref = tm.tileset().ok_or_else(|| {
             let err: GodotString = "tilemap has no tileset assigned!".into();
@@ -89,19 +534,272 @@ impl FlowFieldGenerator {
         })?;
         let dim = self.get_map_dimensions(base)?;
         let ts = unsafe { ts_ref.assume_safe() };
-        let cost = (0..dim.height())
-            .flat_map(|y: usize| {
-                (0..dim.width()).map(move |x: usize| match tm.get_cell(x as i64, y as i64) {
-                    TileMap::INVALID_CELL => Ok(None),
-                    t_idx => self.find_tile_cost(base, &ts.tile_get_name(t_idx).to_string()),
-                })
+        let tiles: Vec<(algo::Cost, Option<algo::EntryCost>)> = (0..dim.max_idx())
+            .map(|idx| {
+                let (x, y) = dim.local_to_cell(dim.unproject_to_field_coords(idx));
+                match tm.get_cell(x as i64, y as i64) {
+                    TileMap::INVALID_CELL => Ok((None, None)),
+                    t_idx => {
+                        self.find_tile_cost(base, &ts.tile_get_name(t_idx).to_string(), profile)
+                    }
+                }
             })
             .try_collect()?;
-        Ok((dim, cost))
+        let (mut cost, entry_costs): (algo::CostField, algo::EntryCostField) =
+            tiles.into_iter().unzip();
+        for (name, overlay) in &self.overlays {
+            if overlay.len() != dim.max_idx() {
+                return Err(format!(
+                    "Overlay {} has {} costs, but the map has {} tiles.",
+                    name,
+                    overlay.len(),
+                    dim.max_idx()
+                )
+                .into());
+            }
+            for (c, o) in cost.iter_mut().zip(overlay) {
+                *c = c.map(|c| c + o);
+            }
+        }
+        let entry_costs = entry_costs
+            .iter()
+            .any(Option::is_some)
+            .then_some(entry_costs);
+        Ok((dim, cost, entry_costs))
+    }
+
+    /**
+     * Register an additive cost layer, or replace the one of the same name.
+     * Expects one cost per tile of the map, row by row. Costs are added to passable tiles only,
+     * negative costs are treated as zero.
+     * Prints errors to console.
+     */
+    #[method]
+    fn set_overlay(&mut self, #[base] base: TRef<'_, Node>, name: String, costs: Float32Array) {
+        let costs = costs.read();
+        match self.get_map_dimensions(base) {
+            Err(m) => godot_error!("FlowFieldGenerator: Error setting overlay: {}", m),
+            Ok(dim) if dim.max_idx() != costs.len() => godot_error!(
+                "FlowFieldGenerator: Bad Parameters. Overlay {} has {} costs, but the map has {} tiles.",
+                name,
+                costs.len(),
+                dim.max_idx()
+            ),
+            Ok(_) if costs.iter().any(|c| !c.is_finite()) => godot_error!(
+                "FlowFieldGenerator: Bad Parameters. Overlay {} has costs that are not finite.",
+                name
+            ),
+            Ok(_) => {
+                self.overlays
+                    .insert(name, costs.iter().map(|c| c.max(0.)).collect());
+                self.overlay_revision += 1
+            }
+        }
+    }
+
+    /**
+     * Remove the cost layer of the given name. Returns whether there was one.
+     */
+    #[method]
+    fn remove_overlay(&mut self, #[base] _base: TRef<'_, Node>, name: String) -> bool {
+        let removed = self.overlays.remove(&name).is_some();
+        if removed {
+            self.overlay_revision += 1
+        }
+        removed
+    }
+
+    /**
+     * Remove all cost layers.
+     */
+    #[method]
+    fn clear_overlays(&mut self, #[base] _base: TRef<'_, Node>) {
+        if !self.overlays.is_empty() {
+            self.overlays.clear();
+            self.overlay_revision += 1
+        }
+    }
+
+    /**
+     * Report the continuous map positions of all agents.
+     * Their crowd density, weighted by `density_weight`, is added to the tile costs of later flow fields,
+     * so that agents spread over parallel routes. An empty array removes the density again.
+     * Prints errors to console.
+     */
+    #[method]
+    fn set_agent_positions(&mut self, #[base] base: TRef<'_, Node>, positions: Vector2Array) {
+        let positions: Vec<algo::Vector2D> = positions.read().iter().map(|p| (p.x, p.y)).collect();
+        if positions.is_empty() {
+            if !self.density.is_empty() {
+                self.density.clear();
+                self.density_revision += 1;
+            }
+            return;
+        }
+        match self.get_map_dimensions(base) {
+            Err(m) => godot_error!("FlowFieldGenerator: Error setting agent positions: {}", m),
+            Ok(dim) => {
+                let positions: Vec<algo::Vector2D> =
+                    positions.iter().map(|&p| dim.pos_to_local(p)).collect();
+                self.density = algo::calculate_density_field(&dim, &positions);
+                self.density_revision += 1
+            }
+        }
+    }
+
+    /// Labels the connected regions of the map for the profile and unit size, and keeps them.
+    fn update_regions(
+        &mut self,
+        base: TRef<'_, Node>,
+        (profile, unit_size): &(String, u64),
+    ) -> Result<(), GodotString> {
+        let (dim, cost, _) = self.generate_cost_field(base, profile)?;
+        let cost = algo::restrict_to_clearance(&dim, cost, *unit_size as usize);
+        let regions = algo::calculate_regions(&dim, self.get_neighborhood(base, &dim)?, &cost);
+        self.regions
+            .insert((profile.to_owned(), *unit_size), (dim, regions));
+        Ok(())
+    }
+
+    /**
+     * Label every tile with the id of its region, row by row, `-1` for impassable tiles.
+     * Tiles of the same region are connected, those of different regions can't reach each other.
+     * Optionally for a movement profile and units covering `unit_size` by `unit_size` tiles,
+     * like `calculate_flow_field`, labelling tiles by the top-left tile of the units' footprint.
+     * The regions are kept for `are_connected`, call this again after the map changed.
+     * Prints errors to console.
+     */
+    #[method]
+    fn compute_regions(
+        &mut self,
+        #[base] base: TRef<'_, Node>,
+        #[opt] profile: String,
+        #[opt] unit_size: u64,
+    ) -> Int32Array {
+        let key = (profile, unit_size.max(1));
+        match self.update_regions(base, &key) {
+            Err(m) => {
+                godot_error!("FlowFieldGenerator: Error calculating cost map: {}", m);
+                Int32Array::new()
+            }
+            Ok(()) => self.regions[&key]
+                .1
+                .iter()
+                .map(|region| region.map_or(-1, |r| r as i32))
+                .collect(),
+        }
+    }
+
+    /**
+     * Whether an agent could get from one position to the other, without calculating a flow field.
+     * Answers from the regions of the last `compute_regions` for the profile and unit size,
+     * computing them on first use.
+     * Directional tile costs are not considered.
+     * Prints errors to console.
+     */
+    #[method]
+    fn are_connected(
+        &mut self,
+        #[base] base: TRef<'_, Node>,
+        a: Vector2,
+        b: Vector2,
+        #[opt] profile: String,
+        #[opt] unit_size: u64,
+    ) -> bool {
+        let (a, b) = match round_vec(a).and_then(|a| Ok((a, round_vec(b)?))) {
+            Err(m) => {
+                godot_error!("FlowFieldGenerator: Bad Parameters. {}", m);
+                return false;
+            }
+            Ok(positions) => positions,
+        };
+        let key = (profile, unit_size.max(1));
+        if !self.regions.contains_key(&key) {
+            if let Err(m) = self.update_regions(base, &key) {
+                godot_error!("FlowFieldGenerator: Error calculating cost map: {}", m);
+                return false;
+            }
+        }
+        let (dim, regions) = &self.regions[&key];
+        let region_at = |cell: algo::Coord| {
+            let (x, y) = dim.cell_to_local(cell);
+            if dim.in_bounds(x, y) {
+                regions[dim.project_to_field_idx(x, y)]
+            } else {
+                None
+            }
+        };
+        region_at(a).map_or(false, |region| region_at(b) == Some(region))
+    }
+
+    /// Runs the integration and flow passes towards the given targets,
+    /// for units of the movement profile covering `unit_size` by `unit_size` tiles.
+    /// Stops the integration at `max_cost`, if given.
+    fn flow_field_towards(
+        &self,
+        base: TRef<'_, Node>,
+        targets: &[algo::Target],
+        profile: &str,
+        unit_size: u64,
+        max_cost: Option<f32>,
+    ) -> Option<Instance<crate::flowfield::FlowField>> {
+        let unit_size = unit_size.max(1);
+        match self
+            .generate_cost_field(base, profile)
+            .and_then(|(dim, cost, entry)| {
+                let settings = self.settings(base, &dim)?;
+                Ok((dim, cost, entry, settings))
+            }) {
+            Ok((dim, cost, entry_costs, settings)) => {
+                let cost = algo::restrict_to_clearance(&dim, cost, unit_size as usize);
+                let entry_costs = entry_costs.as_ref();
+                let targets: Vec<algo::Target> = targets
+                    .iter()
+                    .map(|&(to, initial_cost)| (dim.cell_to_local(to), initial_cost))
+                    .collect();
+                let targets = &settings.snap_targets(&dim, &cost, &targets);
+                let (opt_integration_field, out_of_range) = settings
+                    .integrate_within(
+                        &dim,
+                        &cost,
+                        entry_costs,
+                        targets,
+                        max_cost.unwrap_or(f32::INFINITY),
+                    )
+                    .unzip();
+                let flow_field = opt_integration_field.as_ref().map(|integration_field| {
+                    settings.flow(&dim, &cost, entry_costs, targets, integration_field)
+                });
+                Some(
+                    crate::flowfield::FlowFieldFactory::create(dim, flow_field)
+                        .with_neighborhood(settings.neighborhood)
+                        .with_cell_transform(settings.cell_transform)
+                        .with_unit_size(unit_size)
+                        .with_profile(profile.to_owned())
+                        .with_max_cost(max_cost, out_of_range.unwrap_or_default())
+                        .with_snap_radius(settings.snap_radius as u64)
+                        .with_integration_method(settings.method(entry_costs))
+                        .with_density_revision(self.density_revision)
+                        .with_overlay_revision(self.overlay_revision)
+                        .with_integration(targets.to_vec(), settings.kept(opt_integration_field))
+                        .emplace()
+                        .into_shared(),
+                )
+            }
+            Err(m) => {
+                godot_error!("FlowFieldGenerator: Error calculating cost map: {}", m);
+                None
+            }
+        }
     }
 
     /**
      * Calculate a single flow field to position.
+     * Optionally for a movement profile, using the costs of the tiles' children named like it.
+     * Optionally for units covering `unit_size` by `unit_size` tiles, positioned by their top-left tile.
+     * Tiles where such a unit does not fit are impassable.
+     * Optionally stops integrating at a positive `max_cost`, which is far cheaper for short-range
+     * orders. Tiles costing more than it to reach are left out of range.
      * Prints errors to console.
      */
     #[method]
@@ -109,26 +807,310 @@ impl FlowFieldGenerator {
         &self,
         #[base] base: TRef<'_, Node>,
         to: Vector2,
+        #[opt] profile: String,
+        #[opt] unit_size: u64,
+        #[opt] max_cost: f32,
+    ) -> Option<Instance<crate::flowfield::FlowField>> {
+        let max_cost = (max_cost > 0.).then_some(max_cost);
+        match round_vec(to) {
+            Err(m) => {
+                godot_error!("FlowFieldGenerator: Bad Parameters. {}", m);
+                None
+            }
+            Ok(to) => self.flow_field_towards(base, &[(to, 0.)], &profile, unit_size, max_cost),
+        }
+    }
+
+    /**
+     * Calculate a single flow field towards the nearest of several positions.
+     * Optionally for a movement profile, like `calculate_flow_field`.
+     * Prints errors to console.
+     */
+    #[method]
+    fn calculate_flow_field_multi(
+        &self,
+        #[base] base: TRef<'_, Node>,
+        targets: Vector2Array,
+        #[opt] profile: String,
+    ) -> Option<Instance<crate::flowfield::FlowField>> {
+        let targets: Result<Vec<algo::Target>, GodotString> = targets
+            .read()
+            .iter()
+            .map(|t| Ok((round_vec(*t)?, 0.)))
+            .collect();
+        match targets {
+            Err(m) => {
+                godot_error!("FlowFieldGenerator: Bad Parameters. {}", m);
+                None
+            }
+            Ok(targets) => self.flow_field_towards(base, &targets, &profile, 1, None),
+        }
+    }
+
+    /**
+     * Calculate a single flow field towards every tile within the rectangle.
+     * Agents stop at the nearest tile of the area instead of converging on a single one.
+     * Optionally for a movement profile, like `calculate_flow_field`.
+     * Prints errors to console.
+     */
+    #[method]
+    fn calculate_flow_field_rect(
+        &self,
+        #[base] base: TRef<'_, Node>,
+        area: Rect2,
+        #[opt] profile: String,
     ) -> Option<Instance<crate::flowfield::FlowField>> {
-        let Vector2 { x: to_xf, y: to_yf } = to;
-        if to_xf.is_nan() || to_xf.is_infinite() || to_yf.is_nan() || to_yf.is_infinite() {
-            godot_error!(
-                "FlowFieldGenerator: Bad Parameters. Got {}",
-                to.to_variant()
+        let area = area.abs();
+        let targets = self.get_map_dimensions(base).and_then(|dim| {
+            let (from_x, from_y) = round_vec(area.position)?;
+            let (to_x, to_y) = round_vec(area.end())?;
+            let (origin_x, origin_y) = dim.origin();
+            let (from_x, from_y) = (from_x.max(origin_x), from_y.max(origin_y));
+            let (to_x, to_y) = (
+                to_x.min(origin_x + dim.width() as isize),
+                to_y.min(origin_y + dim.height() as isize),
             );
-            None
+            Ok((from_y..to_y)
+                .flat_map(|y| (from_x..to_x).map(move |x| ((x, y), 0.)))
+                .collect::<Vec<algo::Target>>())
+        });
+        match targets {
+            Err(m) => {
+                godot_error!("FlowFieldGenerator: Bad Parameters. {}", m);
+                None
+            }
+            Ok(targets) => self.flow_field_towards(base, &targets, &profile, 1, None),
+        }
+    }
+
+    /**
+     * Calculate a single flow field towards several positions, each given a starting cost.
+     * Expects a Dictionary of `Vector2` positions to `float` costs,
+     * so that a farther but cheaper target may be preferred over a closer one.
+     * Optionally for a movement profile, like `calculate_flow_field`.
+     * Prints errors to console.
+     */
+    #[method]
+    fn calculate_flow_field_weighted(
+        &self,
+        #[base] base: TRef<'_, Node>,
+        targets: Dictionary,
+        #[opt] profile: String,
+    ) -> Option<Instance<crate::flowfield::FlowField>> {
+        let targets: Result<Vec<algo::Target>, GodotString> = targets
+            .iter()
+            .map(|(k, v)| {
+                let pos = Vector2::from_variant(&k)
+                    .map_err(|e| format!("Target {} is not a Vector2: {}", k, e))?;
+                let initial_cost = v
+                    .try_to::<f32>()
+                    .or_else(|_| v.try_to::<i64>().map(|c| c as f32))
+                    .ok()
+                    .filter(|c| c.is_finite())
+                    .ok_or_else(|| format!("Cost {} of target {} is not a number.", v, k))?;
+                Ok((round_vec(pos)?, initial_cost))
+            })
+            .collect();
+        match targets {
+            Err(m) => {
+                godot_error!("FlowFieldGenerator: Bad Parameters. {}", m);
+                None
+            }
+            Ok(targets) => self.flow_field_towards(base, &targets, &profile, 1, None),
+        }
+    }
+
+    /**
+     * Calculate a flow field leading away from the threats, e.g. for civilians or retreating units.
+     * Agents run towards tiles far from every threat, even past a threat if that is the way out,
+     * instead of cornering themselves. Agents farther than a positive `safety_distance` from all
+     * threats stay where they are.
+     * Optionally for a movement profile, like `calculate_flow_field`.
+     * Prints errors to console.
+     */
+    #[method]
+    fn calculate_flee_field(
+        &self,
+        #[base] base: TRef<'_, Node>,
+        threats: Vector2Array,
+        #[opt] safety_distance: f32,
+        #[opt] profile: String,
+    ) -> Option<Instance<crate::flowfield::FlowField>> {
+        let safety_distance = if safety_distance > 0. {
+            safety_distance
         } else {
-            let (to_x, to_y) = (to_xf as isize, to_yf as isize);
-            match self.generate_cost_field(base) {
-                Ok((dim, cost)) => {
-                    let opt_integration_field =
-                        algo::calculate_integration_field(&dim, (to_x, to_y), &cost);
-                    let flow_field = opt_integration_field.map(|integration_field| {
-                        algo::calculate_flow_field(&dim, &integration_field)
-                    });
+            f32::INFINITY
+        };
+        let threats: Result<Vec<algo::Target>, GodotString> = threats
+            .read()
+            .iter()
+            .map(|t| Ok((round_vec(*t)?, 0.)))
+            .collect();
+        match threats.and_then(|threats| {
+            let (dim, cost, entry_costs) = self.generate_cost_field(base, &profile)?;
+            let settings = self.settings(base, &dim)?;
+            Ok((threats, dim, cost, entry_costs, settings))
+        }) {
+            Ok((threats, dim, cost, entry_costs, settings)) => {
+                let entry_costs = entry_costs.as_ref();
+                let threats: Vec<algo::Target> = threats
+                    .iter()
+                    .map(|&(threat, initial_cost)| (dim.cell_to_local(threat), initial_cost))
+                    .collect();
+                let threats = settings.snap_targets(&dim, &cost, &threats);
+                let opt_integration_field =
+                    settings.integrate_flee(&dim, &cost, entry_costs, &threats, safety_distance);
+                /* every tile is a target of the flee field, line of sight would lead nowhere. */
+                let flow_field = opt_integration_field.as_ref().map(|integration_field| {
+                    settings.flow(&dim, &cost, entry_costs, &[], integration_field)
+                });
+                Some(
+                    crate::flowfield::FlowFieldFactory::create(dim, flow_field)
+                        .with_neighborhood(settings.neighborhood)
+                        .with_cell_transform(settings.cell_transform)
+                        .with_profile(profile)
+                        .with_safety_distance(Some(safety_distance))
+                        .with_snap_radius(settings.snap_radius as u64)
+                        .with_integration_method(settings.method(entry_costs))
+                        .with_density_revision(self.density_revision)
+                        .with_overlay_revision(self.overlay_revision)
+                        .with_integration(threats, settings.kept(opt_integration_field))
+                        .emplace()
+                        .into_shared(),
+                )
+            }
+            Err(m) => {
+                godot_error!("FlowFieldGenerator: Error calculating flee field: {}", m);
+                None
+            }
+        }
+    }
 
+    /**
+     * Repair a flow field after the tiles at the given cells changed, e.g. a building was placed.
+     * Only the region of the integration field affected by the change is recalculated,
+     * the result is identical to calculating the flow field from scratch.
+     * Prints errors to console.
+     */
+    #[method]
+    fn repair_flow_field(
+        &self,
+        #[base] base: TRef<'_, Node>,
+        flow_field: Instance<crate::flowfield::FlowField>,
+        changed_cells: Vector2Array,
+    ) -> Option<Instance<crate::flowfield::FlowField>> {
+        let changed: Result<Vec<algo::Coord>, GodotString> =
+            changed_cells.read().iter().map(|c| round_vec(*c)).collect();
+        let previous = unsafe { flow_field.assume_safe() }.map(|f, _| f.clone());
+        match (changed, previous) {
+            (Err(m), _) => {
+                godot_error!("FlowFieldGenerator: Bad Parameters. {}", m);
+                None
+            }
+            (_, Err(e)) => {
+                godot_error!("FlowFieldGenerator: Error borrowing flow field: {}", e);
+                None
+            }
+            (Ok(changed), Ok(previous)) => match self
+                .generate_cost_field(base, previous.profile())
+                .and_then(|(dim, cost, entry)| {
+                    let settings = self.settings(base, &dim)?;
+                    Ok((dim, cost, entry, settings))
+                }) {
+                Ok((dim, cost, entry_costs, settings)) => {
+                    let unit_size = previous.unit_size() as usize;
+                    let cost = algo::restrict_to_clearance(&dim, cost, unit_size);
+                    let entry_costs = entry_costs.as_ref();
+                    let changed: Vec<algo::Coord> =
+                        changed.iter().map(|&c| dim.cell_to_local(c)).collect();
+                    let changed = algo::clearance_dependents(&changed, unit_size);
+                    /* the map may have grown since, so targets are moved along with its origin. */
+                    let targets: Vec<algo::Target> = previous
+                        .targets()
+                        .iter()
+                        .map(|&(to, initial_cost)| {
+                            (
+                                dim.cell_to_local(previous.dim().local_to_cell(to)),
+                                initial_cost,
+                            )
+                        })
+                        .collect();
+                    /* targets snapped anew only repair the field if they stayed in place. */
+                    let targets = &settings.snap_targets(&dim, &cost, &targets);
+                    let (opt_integration_field, out_of_range) = match (
+                        previous.safety_distance(),
+                        previous.max_cost(),
+                        previous.integration(),
+                    ) {
+                        /* the threats' distances change along with the tiles, so flee anew. */
+                        (Some(safety_distance), _, _) => {
+                            let opt_integration_field = settings.integrate_flee(
+                                &dim,
+                                &cost,
+                                entry_costs,
+                                targets,
+                                safety_distance,
+                            );
+                            (opt_integration_field, vec![])
+                        }
+                        /* repairs expect a complete field, so cut-off fields are integrated anew. */
+                        (None, Some(max_cost), _) => {
+                            let (opt_integration_field, out_of_range) = settings
+                                .integrate_within(&dim, &cost, entry_costs, targets, max_cost)
+                                .unzip();
+                            (opt_integration_field, out_of_range.unwrap_or_default())
+                        }
+                        /* density and overlays touch any tile, repairs need the same revisions.
+                        fields of another integration method can't be repaired into this one. */
+                        (None, None, Some(integration_field))
+                            if *previous.dim() == dim
+                                && previous.neighborhood() == settings.neighborhood
+                                && previous.integration_method()
+                                    == settings.method(entry_costs)
+                                && previous.density_revision() == self.density_revision
+                                && previous.overlay_revision() == self.overlay_revision
+                                && targets == previous.targets() =>
+                        {
+                            let opt_integration_field = settings.reintegrate(
+                                &dim,
+                                &cost,
+                                entry_costs,
+                                targets,
+                                integration_field,
+                                &changed,
+                            );
+                            (opt_integration_field, vec![])
+                        }
+                        _ => (
+                            settings.integrate(&dim, &cost, entry_costs, targets),
+                            vec![],
+                        ),
+                    };
+                    /* flee fields lead away from their targets, never look for them. */
+                    let visible_targets: &[algo::Target] = match previous.safety_distance() {
+                        Some(_) => &[],
+                        None => targets,
+                    };
+                    let flow_field = opt_integration_field.as_ref().map(|integration_field| {
+                        settings.flow(&dim, &cost, entry_costs, visible_targets, integration_field)
+                    });
                     Some(
                         crate::flowfield::FlowFieldFactory::create(dim, flow_field)
+                            .with_neighborhood(settings.neighborhood)
+                            .with_cell_transform(settings.cell_transform)
+                            .with_world_space(previous.world_space())
+                            .with_unit_size(previous.unit_size())
+                            .with_profile(previous.profile().to_owned())
+                            .with_max_cost(previous.max_cost(), out_of_range)
+                            .with_safety_distance(previous.safety_distance())
+                            .with_snap_radius(settings.snap_radius as u64)
+                            .with_integration_method(settings.method(entry_costs))
+                            .with_density_revision(self.density_revision)
+                            .with_overlay_revision(self.overlay_revision)
+                            .with_integration(
+                                targets.to_vec(),
+                                settings.kept(opt_integration_field),
+                            )
                             .emplace()
                             .into_shared(),
                     )
@@ -137,23 +1119,101 @@ impl FlowFieldGenerator {
                     godot_error!("FlowFieldGenerator: Error calculating cost map: {}", m);
                     None
                 }
+            },
+        }
+    }
+
+    /**
+     * Calculate a flow field to position over sectors of `sector_size` tiles, for large maps.
+     * Only the route between sectors is calculated up front,
+     * the flow field of a sector is calculated once an agent queries a tile within it.
+     * Paths are near-optimal only, and always use Dijkstra integration with octile flow.
+     * The crowd density is ignored, so that reporting agent positions keeps the portal graph.
+     * Optionally for a movement profile, like `calculate_flow_field`.
+     * Prints errors to console.
+     */
+    #[method]
+    fn calculate_sector_flow_field(
+        &mut self,
+        #[base] base: TRef<'_, Node>,
+        to: Vector2,
+        #[opt] profile: String,
+    ) -> Option<Instance<crate::flowfield::SectorFlowField>> {
+        let to = match round_vec(to) {
+            Err(m) => {
+                godot_error!("FlowFieldGenerator: Bad Parameters. {}", m);
+                return None;
+            }
+            Ok(to) => to,
+        };
+        match self
+            .generate_uncrowded_cost_field(base, &profile)
+            .and_then(|(dim, cost, entry_costs)| match entry_costs {
+                Some(_) => Err("Sector flow fields do not support directional tile costs.".into()),
+                None => {
+                    let neighborhood = self.get_neighborhood(base, &dim)?;
+                    Ok((dim, cost, neighborhood))
+                }
+            })
+            .and_then(|(dim, cost, n)| Ok((dim, cost, n, self.get_cell_transform(base)?)))
+        {
+            Ok((dim, cost, neighborhood, cell_transform)) => {
+                let radius = self.snap_radius as usize;
+                let to = dim.cell_to_local(to);
+                let to = algo::snap_to_nearest(&dim, neighborhood, to, radius, |idx| {
+                    cost[idx].is_some()
+                })
+                .unwrap_or(to);
+                let sector_size = self.sector_size as usize;
+                let graph = match &self.sector_graph {
+                    Some(graph) if graph.matches(&dim, neighborhood, &cost, sector_size) => {
+                        graph.clone()
+                    }
+                    _ => Arc::new(SectorGraph::new(dim, neighborhood, cost, sector_size)),
+                };
+                self.sector_graph = Some(graph.clone());
+                Some(
+                    SectorFlowFieldFactory::create(
+                        SectorFlow::new(graph, vec![(to, 0.)]),
+                        cell_transform,
+                    )
+                    .with_snap_radius(self.snap_radius)
+                    .emplace()
+                    .into_shared(),
+                )
+            }
+            Err(m) => {
+                godot_error!("FlowFieldGenerator: Error calculating cost map: {}", m);
+                None
             }
         }
     }
 
     /**
      * Calculate all possible flow fields.
+     * Optionally for a movement profile and units covering `unit_size` by `unit_size` tiles,
+     * like `calculate_flow_field`.
      * Prints errors to console.
      */
     #[method]
     fn bake_flowfields(
         &self,
         #[base] base: TRef<'_, Node>,
+        #[opt] profile: String,
+        #[opt] unit_size: u64,
     ) -> Option<Instance<crate::flowfield::BakedFlowFields>> {
-        match self.generate_cost_field(base) {
-            Ok((d, c)) => {
+        let unit_size = unit_size.max(1);
+        match self
+            .generate_cost_field(base, &profile)
+            .and_then(|(d, c, e)| {
+                let settings = self.settings(base, &d)?;
+                Ok((d, c, e, settings))
+            }) {
+            Ok((d, c, e, settings)) => {
+                let c = algo::restrict_to_clearance(&d, c, unit_size as usize);
                 let dim = &d;
                 let cost = &c;
+                let entry_costs = e.as_ref();
                 godot_print!(
                     "FlowFieldGenerator: Baking {} Flow Fields. This might take a while...",
                     dim.max_idx()
@@ -163,12 +1223,20 @@ impl FlowFieldGenerator {
                     .collect::<Vec<(isize, isize)>>()
                     .into_par_iter()
                     .map(move |(x, y)| {
+                        let targets = settings.snap_targets(dim, cost, &[((x, y), 0.)]);
                         let opt_integration_field =
-                            algo::calculate_integration_field(dim, (x, y), cost);
-                        let field = opt_integration_field.map(|integration_field| {
-                            algo::calculate_flow_field(dim, &integration_field)
+                            settings.integrate(dim, cost, entry_costs, &targets);
+                        let field = opt_integration_field.as_ref().map(|integration_field| {
+                            settings.flow(dim, cost, entry_costs, &targets, integration_field)
                         });
                         crate::flowfield::FlowFieldFactory::create(d, field)
+                            .with_neighborhood(settings.neighborhood)
+                            .with_integration_method(settings.method(entry_costs))
+                            .with_snap_radius(settings.snap_radius as u64)
+                            .with_cell_transform(settings.cell_transform)
+                            .with_unit_size(unit_size)
+                            .with_profile(profile.clone())
+                            .with_integration(targets, settings.kept(opt_integration_field))
                     })
                     .collect();
                 godot_print!(
@@ -4,11 +4,13 @@ use gdnative::prelude::*;
 mod algo;
 mod flowfield;
 mod generator;
+mod sectors;
 mod tilecost;
 
 fn init(handle: InitHandle) {
     handle.add_class::<flowfield::FlowField>();
     handle.add_class::<flowfield::BakedFlowFields>();
+    handle.add_class::<flowfield::SectorFlowField>();
     handle.add_tool_class::<tilecost::FlowFieldTileCost>();
     handle.add_tool_class::<generator::FlowFieldGenerator>();
 }
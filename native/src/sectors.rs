@@ -0,0 +1,600 @@
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::algo::{
+    self, Coord, Cost, CostField, Dimensions, Frontier, IntegrationField, Neighborhood, Target,
+    Vector2D,
+};
+
+/// Rectangle of tiles, in map coordinates.
+#[derive(Clone, Copy)]
+struct Bounds {
+    x: isize,
+    y: isize,
+    width: usize,
+    height: usize,
+}
+
+impl Bounds {
+    fn contains(&self, (x, y): Coord) -> bool {
+        x >= self.x
+            && x < self.x + self.width as isize
+            && y >= self.y
+            && y < self.y + self.height as isize
+    }
+
+    fn dim(&self) -> Dimensions {
+        Dimensions::new(self.width, self.height)
+    }
+
+    fn to_local(self, (x, y): Coord) -> Coord {
+        (x - self.x, y - self.y)
+    }
+
+    fn to_global(self, (x, y): Coord) -> Coord {
+        (x + self.x, y + self.y)
+    }
+
+    /// Grows the bounds by a tile in every direction, clipped to the map.
+    fn with_margin(&self, dim: &Dimensions) -> Bounds {
+        let (x, y) = ((self.x - 1).max(0), (self.y - 1).max(0));
+        let end_x = (self.x + self.width as isize + 1).min(dim.width() as isize);
+        let end_y = (self.y + self.height as isize + 1).min(dim.height() as isize);
+        Bounds {
+            x,
+            y,
+            width: (end_x - x) as usize,
+            height: (end_y - y) as usize,
+        }
+    }
+}
+
+/// One side of a portal between two adjacent sectors.
+struct Entrance {
+    /// The entrance on the other side of the sector border.
+    partner: usize,
+    /// All tiles of the portal on this side of the border.
+    window: Vec<Coord>,
+}
+
+/// Portal graph over fixed-size sectors of the map.
+/// Every entrance is connected to the entrances of its sector, through the windows across the border.
+/// Edges are weighted by the highest integration along the entrance's window,
+/// so that an agent flowing into a window never finds a higher cost on the other side,
+/// and can not loop between sectors.
+pub struct SectorGraph {
+    dim: Dimensions,
+    neighborhood: Neighborhood,
+    cost_field: CostField,
+    sector_size: usize,
+    sectors_x: usize,
+    entrances: Vec<Entrance>,
+    sector_entrances: Vec<Vec<usize>>,
+    /// Integration edges, from the entrance closer to the target to the one farther away.
+    edges: Vec<Vec<(usize, f32)>>,
+}
+
+impl SectorGraph {
+    pub fn new(
+        dim: Dimensions,
+        neighborhood: Neighborhood,
+        cost_field: CostField,
+        sector_size: usize,
+    ) -> Self {
+        assert_eq!(
+            dim.max_idx(),
+            cost_field.len(),
+            "Cost field size does not match dimensions!"
+        );
+        let sector_size = sector_size.max(1);
+        let sectors_x = dim.width().div_ceil(sector_size);
+        let sectors_y = dim.height().div_ceil(sector_size);
+        let mut graph = SectorGraph {
+            dim,
+            neighborhood,
+            cost_field,
+            sector_size,
+            sectors_x,
+            entrances: vec![],
+            sector_entrances: vec![vec![]; sectors_x * sectors_y],
+            edges: vec![],
+        };
+
+        /* every step of the neighborhood between two sectors crosses their border,
+        including the shifted steps of hex maps and those into diagonal sectors. */
+        let mut crossings: BTreeMap<(usize, usize), Vec<(Coord, Coord)>> = BTreeMap::new();
+        for idx in (0..dim.max_idx()).filter(|&idx| graph.cost_field[idx].is_some()) {
+            let a = dim.unproject_to_field_coords(idx);
+            for b in algo::passable_neighbors(&dim, neighborhood, &graph.cost_field, a) {
+                let (sector_a, sector_b) = (graph.sector_of(a), graph.sector_of(b));
+                if sector_a < sector_b {
+                    crossings
+                        .entry((sector_a, sector_b))
+                        .or_default()
+                        .push((a, b));
+                }
+            }
+        }
+        for crossings in crossings.values() {
+            graph.add_portals(crossings);
+        }
+
+        /* leaving a sector through an entrance means stepping onto the window of its partner. */
+        let g = &graph;
+        let sector_edges: Vec<(usize, Vec<(usize, f32)>)> = (0..g.sector_entrances.len())
+            .into_par_iter()
+            .flat_map_iter(|sector| {
+                g.sector_entrances[sector].iter().map(move |&exit| {
+                    let partner = g.entrances[exit].partner;
+                    let (m, integration_field) =
+                        g.margin_integration_field(sector, &[(partner, 0.)], &[]);
+                    let reachable = g.sector_entrances[sector]
+                        .iter()
+                        .filter_map(|&idx| Some((idx, g.window_cost(m, &integration_field, idx)?)))
+                        .collect();
+                    (partner, reachable)
+                })
+            })
+            .collect();
+        let mut edges: Vec<Vec<(usize, f32)>> = vec![vec![]; graph.entrances.len()];
+        for (from, reachable) in sector_edges {
+            edges[from].extend(reachable);
+        }
+        graph.edges = edges;
+        graph
+    }
+
+    /// Adds the portals between two sectors, one for every pair of windows with crossings between them.
+    /// A window is a run of border tiles connected within its own sector,
+    /// so that it is either reachable as a whole, or not at all.
+    fn add_portals(&mut self, crossings: &[(Coord, Coord)]) {
+        let (windows_a, window_of_a) = self.windows(crossings.iter().map(|&(a, _)| a));
+        let (windows_b, window_of_b) = self.windows(crossings.iter().map(|&(_, b)| b));
+        let mut portals: Vec<(usize, usize)> = vec![];
+        for (a, b) in crossings {
+            let portal = (window_of_a[a], window_of_b[b]);
+            if !portals.contains(&portal) {
+                portals.push(portal);
+            }
+        }
+        for (a, b) in portals {
+            self.add_portal(windows_a[a].clone(), windows_b[b].clone());
+        }
+    }
+
+    /// Groups border tiles of a sector into windows of tiles connected by the neighborhood.
+    fn windows(
+        &self,
+        tiles: impl Iterator<Item = Coord>,
+    ) -> (Vec<Vec<Coord>>, HashMap<Coord, usize>) {
+        let mut windows: Vec<Vec<Coord>> = vec![];
+        let mut window_of: HashMap<Coord, usize> = HashMap::new();
+        let tiles: Vec<Coord> = tiles.collect();
+        let border: HashSet<Coord> = tiles.iter().copied().collect();
+        for start in tiles {
+            if window_of.contains_key(&start) {
+                continue;
+            }
+            window_of.insert(start, windows.len());
+            let mut window = vec![];
+            let mut stack = vec![start];
+            while let Some(tile) = stack.pop() {
+                window.push(tile);
+                for next in
+                    algo::passable_neighbors(&self.dim, self.neighborhood, &self.cost_field, tile)
+                {
+                    if border.contains(&next) && !window_of.contains_key(&next) {
+                        window_of.insert(next, windows.len());
+                        stack.push(next);
+                    }
+                }
+            }
+            windows.push(window);
+        }
+        (windows, window_of)
+    }
+
+    fn add_portal(&mut self, window_a: Vec<Coord>, window_b: Vec<Coord>) {
+        let (idx_a, idx_b) = (self.entrances.len(), self.entrances.len() + 1);
+        let (sector_a, sector_b) = (self.sector_of(window_a[0]), self.sector_of(window_b[0]));
+        self.entrances.push(Entrance {
+            partner: idx_b,
+            window: window_a,
+        });
+        self.entrances.push(Entrance {
+            partner: idx_a,
+            window: window_b,
+        });
+        self.sector_entrances[sector_a].push(idx_a);
+        self.sector_entrances[sector_b].push(idx_b);
+    }
+
+    pub fn dim(&self) -> &Dimensions {
+        &self.dim
+    }
+
+    /// Whether the graph was built from the same map and settings.
+    pub fn matches(
+        &self,
+        dim: &Dimensions,
+        neighborhood: Neighborhood,
+        cost_field: &CostField,
+        sector_size: usize,
+    ) -> bool {
+        self.dim == *dim
+            && self.neighborhood == neighborhood
+            && self.sector_size == sector_size.max(1)
+            && self.cost_field == *cost_field
+    }
+
+    fn cost_at(&self, (x, y): Coord) -> Cost {
+        if self.dim.in_bounds(x, y) {
+            self.cost_field[self.dim.project_to_field_idx(x, y)]
+        } else {
+            None
+        }
+    }
+
+    fn sector_of(&self, (x, y): Coord) -> usize {
+        x as usize / self.sector_size + (y as usize / self.sector_size) * self.sectors_x
+    }
+
+    fn bounds(&self, sector: usize) -> Bounds {
+        let (sx, sy) = (sector % self.sectors_x, sector / self.sectors_x);
+        let (x, y) = (sx * self.sector_size, sy * self.sector_size);
+        Bounds {
+            x: x as isize,
+            y: y as isize,
+            width: self.sector_size.min(self.dim.width() - x),
+            height: self.sector_size.min(self.dim.height() - y),
+        }
+    }
+
+    /// Integration field of a sector, with a margin of one tile around it.
+    /// Margin tiles are impassable, except for the windows of the given entrances,
+    /// which keep the given cost and seed their neighbors within the sector, just like the targets.
+    /// Windows are never relaxed themselves, as their cost stands for the route beyond the border.
+    fn margin_integration_field(
+        &self,
+        sector: usize,
+        windows: &[(usize, f32)],
+        targets: &[Target],
+    ) -> (Bounds, IntegrationField) {
+        let b = self.bounds(sector);
+        let m = b.with_margin(&self.dim);
+        let margin_dim = m.dim();
+        let neighborhood = self.neighborhood.translated((m.x, m.y));
+        let sector_cost: CostField = (0..m.height as isize)
+            .flat_map(|y| (0..m.width as isize).map(move |x| (x, y)))
+            .map(|local| {
+                let tile = m.to_global(local);
+                if b.contains(tile) {
+                    self.cost_at(tile)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let mut margin_cost = sector_cost.clone();
+        let mut window_costs: Vec<(usize, f32)> = vec![];
+        for &(idx, cost) in windows {
+            for &tile in &self.entrances[idx].window {
+                let (x, y) = m.to_local(tile);
+                let local_idx = margin_dim.project_to_field_idx(x, y);
+                margin_cost[local_idx] = self.cost_at(tile);
+                window_costs.push((local_idx, cost));
+            }
+        }
+        let mut seeds: Vec<Target> = targets
+            .iter()
+            .filter(|(tile, _)| b.contains(*tile))
+            .map(|&(tile, initial_cost)| (m.to_local(tile), initial_cost))
+            .collect();
+        for &(local_idx, cost) in &window_costs {
+            let tile = margin_dim.unproject_to_field_coords(local_idx);
+            seeds.extend(algo::neighbor_seeds(
+                &margin_dim,
+                neighborhood,
+                &margin_cost,
+                tile,
+                cost,
+            ));
+        }
+        seeds.retain(|&(local, _)| b.contains(m.to_global(local)));
+        let mut integration_field = algo::calculate_integration_field(
+            &margin_dim,
+            neighborhood,
+            &seeds,
+            &sector_cost,
+            None,
+        )
+        .unwrap_or_else(|| vec![None; margin_dim.max_idx()]);
+        for (local_idx, cost) in window_costs {
+            if !matches!(integration_field[local_idx], Some(c) if c <= cost) {
+                integration_field[local_idx] = Some(cost);
+            }
+        }
+        (m, integration_field)
+    }
+
+    /// The highest integration along the window of an entrance, `None` if any of its tiles is unreachable.
+    fn window_cost(&self, m: Bounds, integration_field: &IntegrationField, idx: usize) -> Cost {
+        self.entrances[idx]
+            .window
+            .iter()
+            .try_fold(f32::NEG_INFINITY, |highest, &tile| {
+                let (x, y) = m.to_local(tile);
+                integration_field[m.dim().project_to_field_idx(x, y)].map(|c| highest.max(c))
+            })
+    }
+
+    /// Dijkstra over the portal graph, giving every entrance its integration cost towards the targets.
+    /// Entrances of sectors containing targets are seeded with their cost within that sector.
+    fn route_costs(&self, targets: &[Target]) -> Vec<Cost> {
+        let mut route_costs: Vec<Cost> = vec![None; self.entrances.len()];
+        let mut queue: BinaryHeap<Frontier> = BinaryHeap::new();
+        let mut sectors: Vec<usize> = targets
+            .iter()
+            .filter(|&&((x, y), _)| self.dim.in_bounds(x, y))
+            .map(|&(tile, _)| self.sector_of(tile))
+            .collect();
+        sectors.sort_unstable();
+        sectors.dedup();
+        for sector in sectors {
+            let (m, integration_field) = self.margin_integration_field(sector, &[], targets);
+            for &idx in &self.sector_entrances[sector] {
+                if let Some(cost) = self.window_cost(m, &integration_field, idx) {
+                    route_costs[idx] = Some(cost);
+                    queue.push(Frontier { cost, idx });
+                }
+            }
+        }
+
+        let mut settled = vec![false; self.entrances.len()];
+        while let Some(Frontier {
+            cost: current_cost,
+            idx,
+        }) = queue.pop()
+        {
+            if settled[idx] {
+                continue; // stale entry, entrance was already expanded with a lower cost.
+            }
+            settled[idx] = true;
+            for &(idx_next, step) in &self.edges[idx] {
+                let new_cost = step + current_cost;
+                if !matches!(route_costs[idx_next], Some(c) if c <= new_cost) {
+                    route_costs[idx_next] = Some(new_cost);
+                    queue.push(Frontier {
+                        cost: new_cost,
+                        idx: idx_next,
+                    });
+                }
+            }
+        }
+        route_costs
+    }
+
+    /// Local flow field of a single sector, in row-major order of the sector's tiles.
+    /// Calculated with a margin of one tile, where the windows of neighboring entrances
+    /// are seeded with their route cost, so that agents flow across the sector border.
+    fn sector_flow_field(
+        &self,
+        sector: usize,
+        targets: &[Target],
+        route_costs: &[Cost],
+    ) -> algo::FlowField {
+        let windows: Vec<(usize, f32)> = self.sector_entrances[sector]
+            .iter()
+            .filter_map(|&idx| {
+                let partner = self.entrances[idx].partner;
+                route_costs[partner].map(|cost| (partner, cost))
+            })
+            .collect();
+        let (m, integration_field) = self.margin_integration_field(sector, &windows, targets);
+        let margin_dim = m.dim();
+        let flow_field = algo::calculate_flow_field(
+            &margin_dim,
+            self.neighborhood.translated((m.x, m.y)),
+            &integration_field,
+            None,
+        );
+
+        let b = self.bounds(sector);
+        let sector_dim = b.dim();
+        (0..sector_dim.max_idx())
+            .map(|idx| {
+                let tile = b.to_global(sector_dim.unproject_to_field_coords(idx));
+                let (x, y) = m.to_local(tile);
+                flow_field[margin_dim.project_to_field_idx(x, y)]
+            })
+            .collect()
+    }
+}
+
+/// Flow towards targets over a `SectorGraph`.
+/// The route over the portal graph is calculated up front,
+/// local flow fields are only calculated for sectors that are actually queried.
+pub struct SectorFlow {
+    graph: Arc<SectorGraph>,
+    targets: Vec<Target>,
+    route_costs: Vec<Cost>,
+    sector_fields: HashMap<usize, algo::FlowField>,
+}
+
+impl SectorFlow {
+    pub fn new(graph: Arc<SectorGraph>, targets: Vec<Target>) -> Self {
+        let route_costs = graph.route_costs(&targets);
+        SectorFlow {
+            graph,
+            targets,
+            route_costs,
+            sector_fields: HashMap::new(),
+        }
+    }
+
+    pub fn dim(&self) -> &Dimensions {
+        self.graph.dim()
+    }
+
+    pub fn neighborhood(&self) -> Neighborhood {
+        self.graph.neighborhood
+    }
+
+    /// Number of sectors whose local flow field has been calculated so far.
+    pub fn calculated_sectors(&self) -> usize {
+        self.sector_fields.len()
+    }
+
+    /// Flow vector at the tile, calculating the local flow field of its sector if needed.
+    pub fn flow(&mut self, tile @ (x, y): Coord) -> Option<Vector2D> {
+        if !self.graph.dim.in_bounds(x, y) {
+            return None;
+        }
+        let graph = &self.graph;
+        let sector = graph.sector_of(tile);
+        let (targets, route_costs) = (&self.targets, &self.route_costs);
+        let field = self
+            .sector_fields
+            .entry(sector)
+            .or_insert_with(|| graph.sector_flow_field(sector, targets, route_costs));
+        let b = graph.bounds(sector);
+        let (x, y) = b.to_local(tile);
+        field[b.dim().project_to_field_idx(x, y)]
+    }
+
+    /// Flow vector at the tile, or if it can't flow, the direction towards the nearest
+    /// passable tile within `radius`, if that one can. Only the sector of that tile is calculated.
+    pub fn snap_flow(&mut self, tile: Coord, radius: usize) -> Option<Vector2D> {
+        if let Some(v) = self.flow(tile) {
+            return Some(v);
+        }
+        let graph = &self.graph;
+        let nearest = algo::snap_to_nearest(&graph.dim, graph.neighborhood, tile, radius, |idx| {
+            graph.cost_field[idx].is_some()
+        })?;
+        if nearest == tile {
+            return None;
+        }
+        self.flow(nearest)?;
+        Some(algo::direction_to(self.graph.neighborhood, tile, nearest))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::algo::*;
+    use crate::sectors::*;
+
+    #[test]
+    fn sector_flow_crosses_portals_to_target() {
+        let dim = Dimensions::new(12, 8);
+        let mut cost_field = vec![Some(0.); 96];
+        for y in 0..7 {
+            cost_field[dim.project_to_field_idx(4, y)] = None;
+        }
+        let graph = Arc::new(SectorGraph::new(dim, Neighborhood::Eight, cost_field, 4));
+        let mut flow = SectorFlow::new(graph, vec![((11, 7), 0.)]);
+
+        let mut tile = (0, 0);
+        for _ in 0..32 {
+            match flow.flow(tile) {
+                Some((vx, vy)) if vx != 0. || vy != 0. => {
+                    tile = (tile.0 + vx.round() as isize, tile.1 + vy.round() as isize)
+                }
+                _ => break,
+            }
+        }
+        assert_eq!(tile, (11, 7), "agent doesn't arrive at the target.");
+        assert!(
+            flow.calculated_sectors() == 4,
+            "sectors off the route were calculated."
+        )
+    }
+
+    #[test]
+    fn sector_flow_does_not_loop_between_sectors() {
+        let dim = Dimensions::new(17, 13);
+        let cost_field: CostField = (0..dim.max_idx())
+            .map(|idx| match (idx + idx / 17 * 2) % 13 {
+                0 | 1 => None,
+                c => Some(c as f32 * 0.25),
+            })
+            .collect();
+        let target = (9, 6);
+        let integration_field = calculate_integration_field(
+            &dim,
+            Neighborhood::Eight,
+            &[(target, 0.)],
+            &cost_field,
+            None,
+        )
+        .unwrap();
+        let graph = Arc::new(SectorGraph::new(dim, Neighborhood::Eight, cost_field, 4));
+        let mut flow = SectorFlow::new(graph, vec![(target, 0.)]);
+
+        for idx in (0..dim.max_idx()).filter(|&idx| integration_field[idx].is_some()) {
+            let mut tile = dim.unproject_to_field_coords(idx);
+            for _ in 0..dim.max_idx() {
+                match flow.flow(tile) {
+                    Some((vx, vy)) if vx != 0. || vy != 0. => {
+                        tile = (tile.0 + vx.round() as isize, tile.1 + vy.round() as isize)
+                    }
+                    _ => break,
+                }
+            }
+            assert_eq!(tile, target, "agent doesn't arrive at the target.");
+        }
+    }
+
+    #[test]
+    fn sector_flow_crosses_hex_portals() {
+        // The only crossing between the two sectors is the shifted step from (0, 3) to (1, 4).
+        let dim = Dimensions::new(4, 8);
+        let mut cost_field = vec![Some(0.); 32];
+        for x in 0..4 {
+            if x != 0 {
+                cost_field[dim.project_to_field_idx(x, 3)] = None;
+            }
+            if x != 1 {
+                cost_field[dim.project_to_field_idx(x, 4)] = None;
+            }
+        }
+        let target = (3, 7);
+        let integration_field = calculate_integration_field(
+            &dim,
+            Neighborhood::HexOddRows,
+            &[(target, 0.)],
+            &cost_field,
+            None,
+        )
+        .unwrap();
+        let graph = Arc::new(SectorGraph::new(
+            dim,
+            Neighborhood::HexOddRows,
+            cost_field,
+            4,
+        ));
+        let mut flow = SectorFlow::new(graph, vec![(target, 0.)]);
+
+        for idx in (0..dim.max_idx()).filter(|&idx| integration_field[idx].is_some()) {
+            let tile = dim.unproject_to_field_coords(idx);
+            assert!(flow.flow(tile).is_some(), "no flow at {:?}.", tile);
+        }
+    }
+
+    #[test]
+    fn sector_flow_snaps_to_nearest_passable_tile() {
+        let dim = Dimensions::new(12, 8);
+        let mut cost_field = vec![Some(0.); 96];
+        for y in 0..7 {
+            cost_field[dim.project_to_field_idx(4, y)] = None;
+        }
+        let graph = Arc::new(SectorGraph::new(dim, Neighborhood::Eight, cost_field, 4));
+        let mut flow = SectorFlow::new(graph, vec![((11, 7), 0.)]);
+
+        assert_eq!(flow.snap_flow((4, 3), 0), None);
+        assert_eq!(flow.snap_flow((4, 3), 1), Some((-1., 0.)));
+        assert_eq!(flow.snap_flow((5, 3), 1), flow.flow((5, 3)));
+    }
+}
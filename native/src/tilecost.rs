@@ -1,12 +1,30 @@
+use gdnative::export::hint::{EnumHint, IntHint};
 use gdnative::prelude::*;
 
+use crate::algo;
+
+/// Directions of movement, in the order of their bit flags.
+const DIRECTION_NAMES: [&str; 4] = ["North", "East", "South", "West"];
+
 #[derive(NativeClass)]
 #[inherit(Node)]
+#[register_with(Self::register_properties)]
 pub struct FlowFieldTileCost {
     #[property(default = false)]
     pub impassable: bool,
     #[property(default = 1.0)]
     pub cost: f32,
+    /// Bit flags of the directions agents may not move in when stepping onto the tile.
+    pub blocked_entries: i64,
+    /// Extra costs of stepping onto the tile while moving in each direction.
+    #[property(default = 0.0)]
+    pub entry_cost_north: f32,
+    #[property(default = 0.0)]
+    pub entry_cost_east: f32,
+    #[property(default = 0.0)]
+    pub entry_cost_south: f32,
+    #[property(default = 0.0)]
+    pub entry_cost_west: f32,
 }
 
 #[methods]
@@ -15,6 +33,42 @@ impl FlowFieldTileCost {
         Self {
             impassable: false,
             cost: 1.0,
+            blocked_entries: 0,
+            entry_cost_north: 0.0,
+            entry_cost_east: 0.0,
+            entry_cost_south: 0.0,
+            entry_cost_west: 0.0,
+        }
+    }
+
+    fn register_properties(builder: &ClassBuilder<FlowFieldTileCost>) {
+        builder
+            .property::<i64>("blocked_entries")
+            .with_getter(|n: &Self, _base: TRef<Node>| n.blocked_entries)
+            .with_setter(|n: &mut Self, _base: TRef<Node>, new_value: i64| {
+                n.blocked_entries = new_value
+            })
+            .with_hint(IntHint::Flags(EnumHint::new(
+                DIRECTION_NAMES.iter().map(|n| n.to_string()).collect(),
+            )))
+            .with_default(0)
+            .done();
+    }
+
+    /// Costs of stepping onto the tile in each direction, `None` if it may be entered freely.
+    /// Negative extra costs are treated as zero.
+    pub fn entry_cost(&self) -> Option<algo::EntryCost> {
+        let extra = [
+            self.entry_cost_north,
+            self.entry_cost_east,
+            self.entry_cost_south,
+            self.entry_cost_west,
+        ];
+        if self.blocked_entries == 0 && extra.iter().all(|&c| c <= 0.) {
+            return None;
         }
+        Some(std::array::from_fn(|dir| {
+            (self.blocked_entries & (1 << dir) == 0).then(|| extra[dir].max(0.))
+        }))
     }
 }